@@ -0,0 +1,265 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// disassembler
+//////////////////////////////
+
+use super::TRAP_ALIASES;
+use crate::vm::{get_opcode, sign_extend, OpCode, Segment, SymbolTable};
+use std::collections::HashSet;
+
+// NOTE
+// Any word "decodes" to some instruction, so data can't be told apart from code for sure.
+// A word is shown as an instruction only if it is encoded the canonical way
+// (unused bits clear, BR with at least one condition, ...).
+// In particular ASCII characters are BRs with no condition, so strings come out as data.
+
+/// One disassembled word
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    pub addr: u16,
+    pub word: u16,
+    /// Label defined at this address
+    pub label: Option<String>,
+    /// Instruction or `.FILL` directive
+    pub text: String,
+    /// Annotation, such as the character a `.FILL` holds
+    pub comment: Option<String>,
+}
+
+/// Check if a word is an instruction encoded the canonical way
+pub fn is_instruction(word: u16) -> bool {
+    let bits = |hi: u16, lo: u16| (word >> lo) & ((1 << (hi - lo + 1)) - 1);
+    match word >> 12 {
+        // BR needs at least one condition, otherwise it does nothing
+        0x0 => bits(11, 9) != 0,
+        // ADD/AND in register mode have two unused bits
+        0x1 | 0x5 => bits(5, 5) == 1 || bits(4, 3) == 0,
+        0x2 | 0x3 | 0x6 | 0x7 | 0xA | 0xB | 0xE => true,
+        // JSR / JSRR
+        0x4 => bits(11, 11) == 1 || (bits(10, 9) == 0 && bits(5, 0) == 0),
+        0x8 => bits(11, 0) == 0,
+        0x9 => bits(5, 0) == 0x3F,
+        0xC => bits(11, 9) == 0 && bits(5, 0) == 0,
+        0xF => bits(11, 8) == 0,
+        // reserved opcode
+        _ => false,
+    }
+}
+
+/// Render a word in LC-3 syntax, as if it were at `addr`
+///
+/// PC-relative operands become the label at the target if there is one, and the absolute
+/// address otherwise. Words that are not canonical instructions become `.FILL`.
+pub fn disassemble(word: u16, addr: u16, symbols: &SymbolTable) -> String {
+    if !is_instruction(word) {
+        return format!(".FILL x{:04X}", word);
+    }
+
+    let reg = |lo: u16| (word >> lo) & 0x7;
+    let imm = |bits: u16| sign_extend(word & ((1 << bits) - 1), bits as usize) as i16;
+    let target = |bits: u16| {
+        let target = addr.wrapping_add(1).wrapping_add(imm(bits) as u16);
+        match symbols.name_at(target) {
+            Some(name) => name.to_string(),
+            None => format!("x{:04X}", target),
+        }
+    };
+
+    match get_opcode(word) {
+        OpCode::BR => {
+            let mut flags = String::new();
+            for (bit, c) in [(11, 'n'), (10, 'z'), (9, 'p')] {
+                if word & (1 << bit) != 0 {
+                    flags.push(c);
+                }
+            }
+            format!("BR{} {}", flags, target(9))
+        }
+        op @ (OpCode::ADD | OpCode::AND) => {
+            let last = if word & (1 << 5) != 0 {
+                format!("#{}", imm(5))
+            } else {
+                format!("R{}", reg(0))
+            };
+            format!("{:?} R{}, R{}, {}", op, reg(9), reg(6), last)
+        }
+        OpCode::NOT => format!("NOT R{}, R{}", reg(9), reg(6)),
+        op @ (OpCode::LD | OpCode::LDI | OpCode::LEA | OpCode::ST | OpCode::STI) => {
+            format!("{:?} R{}, {}", op, reg(9), target(9))
+        }
+        op @ (OpCode::LDR | OpCode::STR) => {
+            format!("{:?} R{}, R{}, #{}", op, reg(9), reg(6), imm(6))
+        }
+        OpCode::JMP if reg(6) == 7 => "RET".to_string(),
+        OpCode::JMP => format!("JMP R{}", reg(6)),
+        OpCode::JSR if word & (1 << 11) != 0 => format!("JSR {}", target(11)),
+        OpCode::JSR => format!("JSRR R{}", reg(6)),
+        OpCode::RTI => "RTI".to_string(),
+        OpCode::TRAP => {
+            let vector = word & 0xFF;
+            match TRAP_ALIASES.iter().find(|&&(_, v)| v == vector) {
+                Some((alias, _)) => alias.to_string(),
+                None => format!("TRAP x{:02X}", vector),
+            }
+        }
+        _ => format!(".FILL x{:04X}", word),
+    }
+}
+
+/// Disassemble a whole segment
+///
+/// On top of non-canonical words, words that loads and stores point at are taken as data.
+pub fn disassemble_segment(segment: &Segment, symbols: &SymbolTable) -> Vec<DisasmLine> {
+    let range = segment.range();
+    let addrs = || {
+        (range.clone())
+            .map(|addr| addr as u16)
+            .zip(segment.words.iter().copied())
+    };
+
+    let mut data: HashSet<u16> = HashSet::new();
+    for (addr, word) in addrs() {
+        if is_instruction(word)
+            && matches!(
+                get_opcode(word),
+                OpCode::LD | OpCode::LDI | OpCode::ST | OpCode::STI
+            )
+        {
+            let target = addr
+                .wrapping_add(1)
+                .wrapping_add(sign_extend(word & 0x1FF, 9));
+            if range.contains(&(target as usize)) {
+                data.insert(target);
+            }
+        }
+    }
+
+    addrs()
+        .map(|(addr, word)| {
+            let text = if data.contains(&addr) {
+                format!(".FILL x{:04X}", word)
+            } else {
+                disassemble(word, addr, symbols)
+            };
+            let comment = if text.starts_with(".FILL") {
+                describe_data(word)
+            } else {
+                None
+            };
+            DisasmLine {
+                addr,
+                word,
+                label: symbols.name_at(addr).map(str::to_string),
+                text,
+                comment,
+            }
+        })
+        .collect()
+}
+
+// what a data word could mean, as a hint
+fn describe_data(word: u16) -> Option<String> {
+    match word {
+        0 => None,
+        0x0A => Some("'\\n'".to_string()),
+        0x20..=0x7E => Some(format!("'{}'", word as u8 as char)),
+        _ => Some(format!("#{}", word as i16)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn canonical_forms() {
+        let cases: &[(u16, &str)] = &[
+            (0x0E01, "BRnzp x3002"),
+            (0x05FF, "BRz x3000"),
+            // BR with no condition never branches, and is how ASCII text decodes
+            (0x0041, ".FILL x0041"),
+            (0x1242, "ADD R1, R1, R2"),
+            (0x127F, "ADD R1, R1, #-1"),
+            // register mode with the unused bits set
+            (0x1258, ".FILL x1258"),
+            (0x5A20, "AND R5, R0, #0"),
+            (0x5A08, ".FILL x5A08"),
+            (0x927F, "NOT R1, R1"),
+            (0x9240, ".FILL x9240"),
+            (0x4801, "JSR x3002"),
+            (0x40C0, "JSRR R3"),
+            (0x46C0, ".FILL x46C0"),
+            (0x40C1, ".FILL x40C1"),
+            (0xC1C0, "RET"),
+            (0xC0C0, "JMP R3"),
+            (0xC0C1, ".FILL xC0C1"),
+            (0x8000, "RTI"),
+            (0x8001, ".FILL x8001"),
+            (0xD000, ".FILL xD000"),
+            (0x6A7F, "LDR R5, R1, #-1"),
+            (0xE1FF, "LEA R0, x3000"),
+        ];
+        let symbols = SymbolTable::new();
+        for &(word, text) in cases {
+            assert_eq!(disassemble(word, 0x3000, &symbols), text, "x{:04X}", word);
+        }
+    }
+
+    #[test]
+    fn trap_aliases() {
+        let symbols = SymbolTable::new();
+        let names = ["GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT"];
+        for (vector, name) in (0xF020..).zip(names) {
+            assert_eq!(disassemble(vector, 0x3000, &symbols), name);
+        }
+        assert_eq!(disassemble(0xF026, 0x3000, &symbols), "TRAP x26");
+        assert_eq!(disassemble(0xF125, 0x3000, &symbols), ".FILL xF125");
+    }
+
+    #[test]
+    fn segments_tell_data_from_code() {
+        let source = r#"
+            .ORIG x3000
+            LEA R0, MSG
+            PUTS
+            LD R1, VAL
+            HALT
+        VAL .FILL x0E01
+        MSG .STRINGZ "Hi"
+            .END
+        "#;
+        let assembly = assemble("t.asm", source).unwrap();
+        let lines = disassemble_segment(&assembly.segments[0], &assembly.symbols);
+        let text: Vec<(Option<&str>, &str, Option<&str>)> = lines
+            .iter()
+            .map(|l| (l.label.as_deref(), l.text.as_str(), l.comment.as_deref()))
+            .collect();
+        assert_eq!(
+            text,
+            [
+                (None, "LEA R0, MSG", None),
+                (None, "PUTS", None),
+                (None, "LD R1, VAL", None),
+                (None, "HALT", None),
+                // a load target stays data, even though it is a valid instruction
+                (Some("VAL"), ".FILL x0E01", Some("#3585")),
+                (Some("MSG"), ".FILL x0048", Some("'H'")),
+                (None, ".FILL x0069", Some("'i'")),
+                (None, ".FILL x0000", None),
+            ]
+        );
+        assert_eq!(lines[4].addr, 0x3004);
+        assert_eq!(lines[4].word, 0x0E01);
+    }
+}
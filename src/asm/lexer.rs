@@ -0,0 +1,140 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// assembly tokenizer
+//////////////////////////////
+
+use super::{AsmErrorKind, Pos};
+use crate::vm::number;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Opcode, register or label
+    Word,
+    /// Assembler directive, like `.ORIG`
+    Directive,
+    /// Numeric literal (`#-5`, `x3000`, `12`)
+    Number(i32),
+    /// String literal, with escapes resolved
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Text of the token as written
+    pub text: String,
+    /// 1-based column of the first character
+    pub col: usize,
+}
+
+impl Token {
+    /// Position of the token, on a given line
+    pub fn pos(&self, line: usize) -> Pos {
+        Pos {
+            line,
+            col: self.col,
+            token: self.text.clone(),
+        }
+    }
+}
+
+/// Split a line into tokens, dropping commas and the comment
+pub fn tokenize(line: &str) -> Result<Vec<Token>, (AsmErrorKind, usize, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() || c == ',' {
+            i += 1;
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+
+        let start = i;
+        if c == '"' {
+            let (value, end) = string_literal(&chars, i)
+                .map_err(|kind| (kind, start + 1, chars[start..].iter().collect()))?;
+            tokens.push(Token {
+                kind: TokenKind::Str(value),
+                text: chars[start..end].iter().collect(),
+                col: start + 1,
+            });
+            i = end;
+            continue;
+        }
+
+        while i < chars.len() && !(chars[i].is_whitespace() || matches!(chars[i], ',' | ';' | '"'))
+        {
+            i += 1;
+        }
+        let text: String = chars[start..i].iter().collect();
+
+        let kind = if text.starts_with('.') {
+            TokenKind::Directive
+        } else if let Some(n) = number::parse_number(&text, 10) {
+            TokenKind::Number(n)
+        } else if text.starts_with('#')
+            || text.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+        {
+            return Err((AsmErrorKind::BadNumber, start + 1, text));
+        } else if text
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == ':')
+        {
+            TokenKind::Word
+        } else {
+            return Err((AsmErrorKind::BadToken, start + 1, text));
+        };
+        tokens.push(Token {
+            kind,
+            text,
+            col: start + 1,
+        });
+    }
+
+    Ok(tokens)
+}
+
+// read a string literal starting at the opening quote; gives the value and the index past the end
+fn string_literal(chars: &[char], start: usize) -> Result<(String, usize), AsmErrorKind> {
+    let mut value = String::new();
+    let mut i = start + 1;
+    loop {
+        match chars.get(i) {
+            None => return Err(AsmErrorKind::UnterminatedString),
+            Some('"') => return Ok((value, i + 1)),
+            Some('\\') => {
+                let escaped = match chars.get(i + 1) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('e') => '\x1b',
+                    Some('0') => '\0',
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    _ => return Err(AsmErrorKind::BadEscape),
+                };
+                value.push(escaped);
+                i += 2;
+            }
+            Some(&c) => {
+                value.push(c);
+                i += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,662 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// relocatable objects and linking
+//////////////////////////////
+
+use super::Assembly;
+use crate::vm::{loader, Segment, SymbolTable, KBSR};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+// NOTE
+// A relocatable object (`.rel`) is a module assembled at some origin, along with what the linker
+// needs to move it elsewhere. Everything is big endian, like plain objects:
+//   - magic "LC3REL" and a version byte
+//   - origin, word count, then the words
+//   - labels: count, then (address, exported flag, name) for each
+//   - relocations: count, then the address of each word holding a label's absolute address
+//   - fixups: count, then (address, kind, name) for each reference to an imported symbol
+// Names are written as a u16 length followed by UTF-8 bytes.
+
+/// Start of every relocatable object file
+pub const REL_MAGIC: &[u8] = b"LC3REL\x01";
+
+/// How a reference to another module is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixupKind {
+    /// A whole word holding the address (`.FILL`)
+    Word,
+    /// The 9-bit PC offset of BR, LD, LDI, LEA, ST and STI
+    PcOffset9,
+    /// The 11-bit PC offset of JSR
+    PcOffset11,
+}
+
+impl FixupKind {
+    fn from_u8(b: u8) -> Option<FixupKind> {
+        match b {
+            0 => Some(FixupKind::Word),
+            1 => Some(FixupKind::PcOffset9),
+            2 => Some(FixupKind::PcOffset11),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            FixupKind::Word => 0,
+            FixupKind::PcOffset9 => 1,
+            FixupKind::PcOffset11 => 2,
+        }
+    }
+}
+
+impl fmt::Display for FixupKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixupKind::Word => write!(f, "word"),
+            FixupKind::PcOffset9 => write!(f, "PCoffset9"),
+            FixupKind::PcOffset11 => write!(f, "PCoffset11"),
+        }
+    }
+}
+
+/// A reference to an imported symbol, to be filled in by the linker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixup {
+    /// Address of the word to patch (as assembled)
+    pub addr: u16,
+    pub kind: FixupKind,
+    pub symbol: String,
+}
+
+/// Why a module could not be read or linked
+#[derive(Debug)]
+pub enum LinkError {
+    Io(io::Error),
+    /// The relocatable object is malformed
+    Corrupt {
+        offset: usize,
+        reason: &'static str,
+    },
+    /// Relocatable modules hold exactly one `.ORIG` block
+    NotOneSection {
+        module: String,
+        sections: usize,
+    },
+    /// Two modules export the same symbol
+    DuplicateSymbol {
+        symbol: String,
+        first: String,
+        second: String,
+    },
+    /// An imported symbol is not exported by any module
+    UndefinedSymbol {
+        symbol: String,
+        module: String,
+        addr: u16,
+    },
+    /// A PC-relative reference can't reach its target
+    OutOfRange {
+        symbol: String,
+        module: String,
+        addr: u16,
+        kind: FixupKind,
+        offset: i32,
+    },
+    /// The linked program does not fit below the device registers
+    TooLarge {
+        range: Range<usize>,
+    },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::Io(e) => write!(f, "{}", e),
+            LinkError::Corrupt { offset, reason } => {
+                write!(
+                    f,
+                    "corrupt relocatable object at byte {}: {}",
+                    offset, reason
+                )
+            }
+            LinkError::NotOneSection { module, sections } => write!(
+                f,
+                "{}: a relocatable module needs exactly one .ORIG block, found {}",
+                module, sections
+            ),
+            LinkError::DuplicateSymbol {
+                symbol,
+                first,
+                second,
+            } => write!(
+                f,
+                "`{}` is exported by both {} and {}",
+                symbol, first, second
+            ),
+            LinkError::UndefinedSymbol {
+                symbol,
+                module,
+                addr,
+            } => write!(
+                f,
+                "{}: undefined symbol `{}` referenced at x{:04X}",
+                module, symbol, addr
+            ),
+            LinkError::OutOfRange {
+                symbol,
+                module,
+                addr,
+                kind,
+                offset,
+            } => write!(
+                f,
+                "{}: `{}` is {} words away from x{:04X}, out of range for a {}",
+                module, symbol, offset, addr, kind
+            ),
+            LinkError::TooLarge { range } => write!(
+                f,
+                "linked program at {} does not fit below the device registers",
+                loader::fmt_range(range)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+impl From<io::Error> for LinkError {
+    fn from(e: io::Error) -> Self {
+        LinkError::Io(e)
+    }
+}
+
+/// Every error found while linking
+#[derive(Debug)]
+pub struct LinkErrors(pub Vec<LinkError>);
+
+impl fmt::Display for LinkErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LinkErrors {}
+
+////////////////
+// modules
+////////////////
+
+/// A relocatable module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    /// Name used in error messages (not stored in the object file)
+    pub name: String,
+    /// Address the module was assembled at
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// Every label, and whether it is exported
+    pub labels: Vec<(String, u16, bool)>,
+    pub relocations: Vec<u16>,
+    pub fixups: Vec<Fixup>,
+}
+
+impl Module {
+    /// Make a module out of an assembled program
+    pub fn from_assembly(name: &str, assembly: &Assembly) -> Result<Module, LinkError> {
+        let [segment] = &assembly.segments[..] else {
+            return Err(LinkError::NotOneSection {
+                module: name.to_string(),
+                sections: assembly.segments.len(),
+            });
+        };
+
+        let labels = assembly
+            .symbols
+            .iter()
+            .map(|(label, addr)| {
+                let exported = assembly.exports.iter().any(|e| e == label);
+                (label.to_string(), addr, exported)
+            })
+            .collect();
+
+        Ok(Module {
+            name: name.to_string(),
+            origin: segment.origin,
+            words: segment.words.clone(),
+            labels,
+            relocations: assembly.relocations.clone(),
+            fixups: assembly.fixups.clone(),
+        })
+    }
+
+    /// Parse a relocatable object
+    pub fn read(name: &str, bytes: &[u8]) -> Result<Module, LinkError> {
+        let Some(mut r) = bytes.strip_prefix(REL_MAGIC) else {
+            return Err(LinkError::Corrupt {
+                offset: 0,
+                reason: "not a relocatable object",
+            });
+        };
+
+        // any read running off the end means the file was cut short
+        let truncated = |r: &[u8]| LinkError::Corrupt {
+            offset: bytes.len() - r.len(),
+            reason: "truncated",
+        };
+        let mut parse = || -> io::Result<Module> {
+            let origin = r.read_u16::<BigEndian>()?;
+            let len = r.read_u16::<BigEndian>()?;
+            let words = (0..len)
+                .map(|_| r.read_u16::<BigEndian>())
+                .collect::<io::Result<_>>()?;
+
+            let mut labels = Vec::new();
+            for _ in 0..r.read_u16::<BigEndian>()? {
+                let addr = r.read_u16::<BigEndian>()?;
+                let exported = r.read_u8()? != 0;
+                labels.push((read_name(&mut r)?, addr, exported));
+            }
+
+            let relocations = (0..r.read_u16::<BigEndian>()?)
+                .map(|_| r.read_u16::<BigEndian>())
+                .collect::<io::Result<_>>()?;
+
+            let mut fixups = Vec::new();
+            for _ in 0..r.read_u16::<BigEndian>()? {
+                let addr = r.read_u16::<BigEndian>()?;
+                let kind = FixupKind::from_u8(r.read_u8()?)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "fixup kind"))?;
+                fixups.push(Fixup {
+                    addr,
+                    kind,
+                    symbol: read_name(&mut r)?,
+                });
+            }
+
+            Ok(Module {
+                name: name.to_string(),
+                origin,
+                words,
+                labels,
+                relocations,
+                fixups,
+            })
+        };
+
+        match parse() {
+            Ok(_) if !r.is_empty() => Err(LinkError::Corrupt {
+                offset: bytes.len() - r.len(),
+                reason: "trailing data",
+            }),
+            Ok(module) => {
+                let range = module.origin as usize..module.origin as usize + module.words.len();
+                let inside = |addr: &u16| range.contains(&(*addr as usize));
+                if module.relocations.iter().all(inside)
+                    && module.fixups.iter().all(|f| inside(&f.addr))
+                {
+                    Ok(module)
+                } else {
+                    Err(LinkError::Corrupt {
+                        offset: bytes.len(),
+                        reason: "relocation or fixup outside of the module",
+                    })
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(LinkError::Corrupt {
+                offset: bytes.len() - r.len(),
+                reason: "invalid fixup kind or name",
+            }),
+            Err(_) => Err(truncated(r)),
+        }
+    }
+
+    /// Write the module as a relocatable object
+    pub fn write(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(REL_MAGIC)?;
+        w.write_u16::<BigEndian>(self.origin)?;
+        w.write_u16::<BigEndian>(self.words.len() as u16)?;
+        for &word in &self.words {
+            w.write_u16::<BigEndian>(word)?;
+        }
+
+        w.write_u16::<BigEndian>(self.labels.len() as u16)?;
+        for (label, addr, exported) in &self.labels {
+            w.write_u16::<BigEndian>(*addr)?;
+            w.write_u8(*exported as u8)?;
+            write_name(&mut w, label)?;
+        }
+
+        w.write_u16::<BigEndian>(self.relocations.len() as u16)?;
+        for &addr in &self.relocations {
+            w.write_u16::<BigEndian>(addr)?;
+        }
+
+        w.write_u16::<BigEndian>(self.fixups.len() as u16)?;
+        for fixup in &self.fixups {
+            w.write_u16::<BigEndian>(fixup.addr)?;
+            w.write_u8(fixup.kind.to_u8())?;
+            write_name(&mut w, &fixup.symbol)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_name(r: &mut impl Read) -> io::Result<String> {
+    let len = r.read_u16::<BigEndian>()?;
+    let mut bytes = vec![0; len as usize];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "name"))
+}
+
+fn write_name(w: &mut impl Write, name: &str) -> io::Result<()> {
+    w.write_u16::<BigEndian>(name.len() as u16)?;
+    w.write_all(name.as_bytes())
+}
+
+////////////////
+// linking
+////////////////
+
+/// A linked program, ready to be written as a plain object
+#[derive(Debug, Clone)]
+pub struct Linked {
+    pub segment: Segment,
+    /// Exported labels, and the local labels that don't clash with them
+    pub symbols: SymbolTable,
+    /// Where each module was placed, in the order given
+    pub placements: Vec<Range<usize>>,
+}
+
+/// Place modules one after the other starting at `base`, and resolve their references
+pub fn link(modules: &[Module], base: u16) -> Result<Linked, LinkErrors> {
+    let mut errors = Vec::new();
+
+    // placement
+    let mut placements = Vec::new();
+    let mut next = base as usize;
+    for module in modules {
+        placements.push(next..next + module.words.len());
+        next += module.words.len();
+    }
+    let range = base as usize..next;
+    // the device registers start at KBSR
+    if range.end > KBSR as usize {
+        return Err(LinkErrors(vec![LinkError::TooLarge { range }]));
+    }
+
+    // moves an address of a module to where the module was placed
+    let relocate = |i: usize, addr: u16| {
+        addr.wrapping_sub(modules[i].origin)
+            .wrapping_add(placements[i].start as u16)
+    };
+
+    // exported symbols, with the module that defines them
+    let mut exports: HashMap<&str, (u16, usize)> = HashMap::new();
+    for (i, module) in modules.iter().enumerate() {
+        for (label, addr, _) in module.labels.iter().filter(|l| l.2) {
+            if let Some(&(_, first)) = exports.get(label.as_str()) {
+                errors.push(LinkError::DuplicateSymbol {
+                    symbol: label.clone(),
+                    first: modules[first].name.clone(),
+                    second: module.name.clone(),
+                });
+                continue;
+            }
+            exports.insert(label, (relocate(i, *addr), i));
+        }
+    }
+
+    let mut words = Vec::with_capacity(range.len());
+    for (i, module) in modules.iter().enumerate() {
+        let start = words.len();
+        words.extend_from_slice(&module.words);
+        let word_at = |addr: u16| start + (addr - module.origin) as usize;
+
+        for &addr in &module.relocations {
+            let word = &mut words[word_at(addr)];
+            *word = relocate(i, *word);
+        }
+
+        for fixup in &module.fixups {
+            let Some(&(target, _)) = exports.get(fixup.symbol.as_str()) else {
+                errors.push(LinkError::UndefinedSymbol {
+                    symbol: fixup.symbol.clone(),
+                    module: module.name.clone(),
+                    addr: relocate(i, fixup.addr),
+                });
+                continue;
+            };
+
+            let word = &mut words[word_at(fixup.addr)];
+            let bits = match fixup.kind {
+                FixupKind::Word => {
+                    *word = target;
+                    continue;
+                }
+                FixupKind::PcOffset9 => 9,
+                FixupKind::PcOffset11 => 11,
+            };
+
+            let addr = relocate(i, fixup.addr);
+            let offset = target as i32 - (addr as i32 + 1);
+            if offset < -(1 << (bits - 1)) || offset >= 1 << (bits - 1) {
+                errors.push(LinkError::OutOfRange {
+                    symbol: fixup.symbol.clone(),
+                    module: module.name.clone(),
+                    addr,
+                    kind: fixup.kind,
+                    offset,
+                });
+                continue;
+            }
+            let mask = (1 << bits) - 1;
+            *word = (*word & !mask) | (offset as u16 & mask);
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(LinkErrors(errors));
+    }
+
+    let mut symbols = SymbolTable::new();
+    for (label, &(addr, _)) in &exports {
+        symbols.insert(label, addr);
+    }
+    for (i, module) in modules.iter().enumerate() {
+        for (label, addr, _) in &module.labels {
+            if symbols.get(label).is_none() {
+                symbols.insert(label, relocate(i, *addr));
+            }
+        }
+    }
+
+    Ok(Linked {
+        segment: Segment {
+            origin: base,
+            words,
+        },
+        symbols,
+        placements,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn module(name: &str, source: &str) -> Module {
+        let assembly = assemble(name, source).unwrap();
+        Module::from_assembly(name, &assembly).unwrap()
+    }
+
+    // a module with a PC-relative reference to FAR, `gap` words past it
+    fn caller(op: &str, gap: usize) -> Module {
+        let source = format!(
+            ".ORIG x3000\n.IMPORT FAR\n{} FAR\n.BLKW {}\n.END\n",
+            op, gap
+        );
+        module("main.asm", &source)
+    }
+
+    // a module exporting FAR, followed by `gap` words
+    fn callee(gap: usize) -> Module {
+        let source = format!(
+            ".ORIG x3000\n.EXPORT FAR\nFAR .FILL 0\n.BLKW {}\n.END\n",
+            gap
+        );
+        module("lib.asm", &source)
+    }
+
+    fn out_of_range(result: Result<Linked, LinkErrors>) -> bool {
+        matches!(&result, Err(LinkErrors(e)) if matches!(e[..], [LinkError::OutOfRange { .. }]))
+    }
+
+    #[test]
+    fn relocates_fill_label() {
+        let main = module(
+            "main.asm",
+            ".ORIG x3000\n.IMPORT VALUE\nPTR .FILL DATA\nEXT .FILL VALUE\nDATA .FILL x1234\n.END\n",
+        );
+        let lib = module(
+            "lib.asm",
+            ".ORIG x5000\n.EXPORT VALUE\nPAD .FILL PAD\nVALUE .FILL 7\n.END\n",
+        );
+        let linked = link(&[main, lib], 0x4000).unwrap();
+
+        assert_eq!(linked.segment.origin, 0x4000);
+        // local labels move with their module, imported ones point into the other module
+        assert_eq!(linked.segment.words, [0x4002, 0x4004, 0x1234, 0x4003, 7]);
+        assert_eq!(linked.placements, [0x4000..0x4003, 0x4003..0x4005]);
+        assert_eq!(linked.symbols.get("VALUE"), Some(0x4004));
+        assert_eq!(linked.symbols.get("DATA"), Some(0x4002));
+    }
+
+    #[test]
+    fn pc_offset9_range() {
+        // forwards: the LD at x3000 reaches up to x3001 + 255
+        let linked = link(&[caller("LD R0,", 255), callee(0)], 0x3000).unwrap();
+        assert_eq!(linked.segment.words[0], 0b0010_0000_1111_1111);
+        assert!(out_of_range(link(
+            &[caller("LD R0,", 256), callee(0)],
+            0x3000
+        )));
+
+        // backwards: down to x3001 - 256 from an LD at x3000
+        let linked = link(&[callee(254), caller("LD R0,", 0)], 0x3000).unwrap();
+        assert_eq!(linked.segment.words[255], 0b0010_0001_0000_0000);
+        assert!(out_of_range(link(
+            &[callee(255), caller("LD R0,", 0)],
+            0x3000
+        )));
+    }
+
+    #[test]
+    fn pc_offset11_range() {
+        let linked = link(&[caller("JSR", 1023), callee(0)], 0x3000).unwrap();
+        assert_eq!(linked.segment.words[0], 0b0100_1011_1111_1111);
+        assert!(out_of_range(link(
+            &[caller("JSR", 1024), callee(0)],
+            0x3000
+        )));
+
+        let linked = link(&[callee(1022), caller("JSR", 0)], 0x3000).unwrap();
+        assert_eq!(linked.segment.words[1023], 0b0100_1100_0000_0000);
+        assert!(out_of_range(link(
+            &[callee(1023), caller("JSR", 0)],
+            0x3000
+        )));
+    }
+
+    #[test]
+    fn reports_symbol_errors() {
+        let Err(LinkErrors(errors)) = link(&[caller("JSR", 0)], 0x3000) else {
+            panic!("linked with an undefined symbol");
+        };
+        assert_eq!(
+            errors[0].to_string(),
+            "main.asm: undefined symbol `FAR` referenced at x3000"
+        );
+
+        let Err(LinkErrors(errors)) = link(&[callee(0), callee(0)], 0x3000) else {
+            panic!("linked with a duplicate symbol");
+        };
+        assert_eq!(
+            errors[0].to_string(),
+            "`FAR` is exported by both lib.asm and lib.asm"
+        );
+
+        assert!(matches!(
+            link(&[callee(0)], 0xFE00).unwrap_err().0[..],
+            [LinkError::TooLarge { .. }]
+        ));
+    }
+
+    #[test]
+    fn object_round_trip() {
+        let main = caller("JSR", 3);
+        let mut bytes = Vec::new();
+        main.write(&mut bytes).unwrap();
+        assert_eq!(Module::read("main.asm", &bytes).unwrap(), main);
+    }
+
+    #[test]
+    fn rejects_corrupt_objects() {
+        let mut bytes = Vec::new();
+        module(
+            "main.asm",
+            ".ORIG x3000\n.IMPORT FAR\nA .FILL A\nJSR FAR\n.END\n",
+        )
+        .write(&mut bytes)
+        .unwrap();
+        let corrupt = |bytes: &[u8]| match Module::read("main.rel", bytes) {
+            Err(LinkError::Corrupt { offset, reason }) => (offset, reason),
+            other => panic!("read a corrupt object: {:?}", other),
+        };
+
+        assert_eq!(corrupt(b"LC3"), (0, "not a relocatable object"));
+        assert_eq!(corrupt(&[0x30, 0x00]), (0, "not a relocatable object"));
+        // cut anywhere after the magic
+        for len in REL_MAGIC.len()..bytes.len() {
+            assert_eq!(corrupt(&bytes[..len]).1, "truncated", "cut at {}", len);
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(corrupt(&trailing), (bytes.len(), "trailing data"));
+
+        // the fixup is last: address, kind, then the name
+        let kind = bytes.len() - "FAR".len() - 2 - 1;
+        let mut bad_kind = bytes.clone();
+        bad_kind[kind] = 9;
+        assert_eq!(corrupt(&bad_kind).1, "invalid fixup kind or name");
+
+        let mut outside = bytes.clone();
+        outside[kind - 2..kind].copy_from_slice(&[0x40, 0x00]);
+        assert_eq!(
+            corrupt(&outside).1,
+            "relocation or fixup outside of the module"
+        );
+    }
+}
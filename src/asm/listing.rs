@@ -0,0 +1,154 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// listing files
+//////////////////////////////
+
+use super::{disassemble_segment, Assembly};
+use crate::vm::{Segment, SymbolTable};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+// NOTE
+// A listing shows, for each word: its address, its value in hex and binary, the label defined
+// there, and the source line it came from. Source lines that produce no words (comments,
+// .ORIG, ...) still appear, with the first columns left blank, and lines that produce several
+// words (.STRINGZ, .BLKW) are followed by rows for the extra words.
+
+const HEADER: &str = "Addr   Hex   Binary            Symbol            Line  Source";
+
+/// Listing of an assembled program, next to the source it came from
+pub fn listing(assembly: &Assembly, source: &str) -> String {
+    let words = word_map(&assembly.segments);
+
+    // addresses of the words each line produced
+    let mut addrs: BTreeMap<usize, Vec<u16>> = BTreeMap::new();
+    for (&addr, &line) in &assembly.source_map {
+        addrs.entry(line).or_default().push(addr);
+    }
+
+    let mut out = format!("{}\n", HEADER);
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let text = text.trim_end();
+        match addrs.get(&line).map(Vec::as_slice) {
+            None | Some([]) => row(&mut out, None, "", Some(line), text),
+            Some([first, rest @ ..]) => {
+                let word = |addr: &u16| (*addr, words.get(addr).copied().unwrap_or(0));
+                let label = |addr: &u16| assembly.symbols.name_at(*addr).unwrap_or("");
+                row(&mut out, Some(word(first)), label(first), Some(line), text);
+                for addr in rest {
+                    row(&mut out, Some(word(addr)), label(addr), None, "");
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Listing of a program with no source, using its disassembly in place of the source
+pub fn listing_from_disassembly(segments: &[Segment], symbols: &SymbolTable) -> String {
+    let mut out = format!("{}\n", HEADER);
+    for segment in segments {
+        row(
+            &mut out,
+            None,
+            "",
+            None,
+            &format!(".ORIG x{:04X}", segment.origin),
+        );
+        for line in disassemble_segment(segment, symbols) {
+            let text = match &line.comment {
+                Some(comment) => format!("{:24}; {}", line.text, comment),
+                None => line.text,
+            };
+            row(
+                &mut out,
+                Some((line.addr, line.word)),
+                line.label.as_deref().unwrap_or(""),
+                None,
+                &text,
+            );
+        }
+        row(&mut out, None, "", None, ".END");
+    }
+    out
+}
+
+fn word_map(segments: &[Segment]) -> HashMap<u16, u16> {
+    segments
+        .iter()
+        .flat_map(|s| {
+            s.range()
+                .map(|addr| addr as u16)
+                .zip(s.words.iter().copied())
+        })
+        .collect()
+}
+
+// one row of the listing; columns are blank where there is nothing to show
+fn row(out: &mut String, word: Option<(u16, u16)>, label: &str, line: Option<usize>, text: &str) {
+    let columns = match word {
+        Some((addr, word)) => format!("x{:04X}  {:04X}  {:016b}", addr, word, word),
+        None => String::new(),
+    };
+    let line = line.map(|l| l.to_string()).unwrap_or_default();
+    let row = format!("{:29}  {:16}  {:>4}  {}", columns, label, line, text);
+    let _ = writeln!(out, "{}", row.trim_end());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn listing_format() {
+        let source = "\
+; comment
+        .ORIG x3000
+MAIN    LEA R0, MSG
+        HALT
+MSG     .STRINGZ \"ok\"
+        .END
+";
+        let assembly = assemble("t.asm", source).unwrap();
+        let expected = "\
+Addr   Hex   Binary            Symbol            Line  Source
+                                                    1  ; comment
+                                                    2          .ORIG x3000
+x3000  E001  1110000000000001  MAIN                 3  MAIN    LEA R0, MSG
+x3001  F025  1111000000100101                       4          HALT
+x3002  006F  0000000001101111  MSG                  5  MSG     .STRINGZ \"ok\"
+x3003  006B  0000000001101011
+x3004  0000  0000000000000000
+                                                    6          .END
+";
+        assert_eq!(listing(&assembly, source), expected);
+
+        // without the source, the disassembly stands in for it
+        let expected = "\
+Addr   Hex   Binary            Symbol            Line  Source
+                                                       .ORIG x3000
+x3000  E001  1110000000000001  MAIN                    LEA R0, MSG
+x3001  F025  1111000000100101                          HALT
+x3002  006F  0000000001101111  MSG                     .FILL x006F             ; 'o'
+x3003  006B  0000000001101011                          .FILL x006B             ; 'k'
+x3004  0000  0000000000000000                          .FILL x0000
+                                                       .END
+";
+        assert_eq!(
+            listing_from_disassembly(&assembly.segments, &assembly.symbols),
+            expected
+        );
+    }
+}
@@ -0,0 +1,1026 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// assembler
+//////////////////////////////
+
+//! LC-3 assembler.
+//!
+//! Supports the whole instruction set, the trap aliases (`GETC`, `OUT`, `PUTS`, `IN`, `PUTSP`,
+//! `HALT`), labels, and the `.ORIG`, `.FILL`, `.BLKW`, `.STRINGZ` and `.END` directives.
+//! A source file may hold several `.ORIG` blocks, each becoming its own [`Segment`].
+//!
+//! For linking (see [`link`]), `.EXPORT LABEL` makes a label visible to other modules,
+//! and `.IMPORT LABEL` declares one that another module defines.
+//!
+//! Mnemonics, directives and registers are case-insensitive; labels are not.
+//! Numbers are written `#10`, `x3000` (or `0x3000`), `b1010`, or as bare decimals.
+//! A word that reads as a number is one, so `xAB` or `b10` can't be used as labels.
+
+mod disasm;
+mod lexer;
+pub mod link;
+mod listing;
+
+pub use disasm::{disassemble, disassemble_segment, is_instruction, DisasmLine};
+pub use listing::{listing, listing_from_disassembly};
+
+use crate::vm::{OpCode, Segment, SymbolTable};
+use lexer::{Token, TokenKind};
+use link::{Fixup, FixupKind};
+use std::collections::BTreeMap;
+use std::fmt;
+
+////////////////
+// results and errors
+////////////////
+
+/// Output of the assembler
+#[derive(Debug, Clone, Default)]
+pub struct Assembly {
+    /// One segment per `.ORIG` block
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+    /// Source line (1-based) that every assembled word came from
+    pub source_map: BTreeMap<u16, usize>,
+    /// Labels made visible to other modules with `.EXPORT`
+    pub exports: Vec<String>,
+    /// Symbols declared with `.IMPORT`
+    pub imports: Vec<String>,
+    /// Words holding the absolute address of a label, which change if the program is moved
+    pub relocations: Vec<u16>,
+    /// References to imported symbols, left for the linker to fill in
+    pub fixups: Vec<Fixup>,
+}
+
+/// Location of a token in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pos {
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column
+    pub col: usize,
+    /// The offending token (empty at the end of a line)
+    pub token: String,
+}
+
+/// What went wrong while assembling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    BadNumber,
+    BadToken,
+    UnterminatedString,
+    BadEscape,
+    UnknownInstruction,
+    /// An operand of the given kind was expected
+    Expected(&'static str),
+    ExtraOperand,
+    OutOfRange {
+        min: i32,
+        max: i32,
+    },
+    InvalidLabel,
+    DuplicateLabel,
+    UndefinedLabel,
+    /// Code or data before any `.ORIG` (or after `.END`)
+    OutsideOrig,
+    /// The block runs past xFFFF
+    MemoryOverflow,
+}
+
+/// An assembly error, pointing at the token that caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub pos: Pos,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: ", self.file, self.pos.line, self.pos.col)?;
+
+        let token = &self.pos.token;
+        match &self.kind {
+            AsmErrorKind::BadNumber => write!(f, "invalid number `{}`", token),
+            AsmErrorKind::BadToken => write!(f, "unexpected `{}`", token),
+            AsmErrorKind::UnterminatedString => write!(f, "unterminated string `{}`", token),
+            AsmErrorKind::BadEscape => write!(f, "invalid escape sequence in `{}`", token),
+            AsmErrorKind::UnknownInstruction => {
+                write!(f, "unknown instruction or directive `{}`", token)
+            }
+            AsmErrorKind::Expected(what) if token.is_empty() => {
+                write!(f, "expected {}, found end of line", what)
+            }
+            AsmErrorKind::Expected(what) => write!(f, "expected {}, found `{}`", what, token),
+            AsmErrorKind::ExtraOperand => write!(f, "unexpected operand `{}`", token),
+            AsmErrorKind::OutOfRange { min, max } => {
+                write!(f, "`{}` is out of range ({} to {})", token, min, max)
+            }
+            AsmErrorKind::InvalidLabel => write!(f, "`{}` is not a valid label", token),
+            AsmErrorKind::DuplicateLabel => write!(f, "label `{}` is already defined", token),
+            AsmErrorKind::UndefinedLabel => write!(f, "undefined label `{}`", token),
+            AsmErrorKind::OutsideOrig => write!(f, "`{}` is outside of any .ORIG block", token),
+            AsmErrorKind::MemoryOverflow => {
+                write!(f, "`{}` runs past the end of memory", token)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Every error found in a source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmErrors(pub Vec<AsmError>);
+
+impl fmt::Display for AsmErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AsmErrors {}
+
+fn error(kind: AsmErrorKind, pos: Pos) -> AsmError {
+    // the file name is filled in by `assemble`
+    AsmError {
+        file: String::new(),
+        pos,
+        kind,
+    }
+}
+
+////////////////
+// assembling
+////////////////
+
+// a line holding an instruction or directive
+struct Statement {
+    line: usize,
+    // upper-cased name of the instruction or directive
+    name: String,
+    mnemonic: Token,
+    operands: Vec<Token>,
+    // column just past the last token, for "found end of line" errors
+    end_col: usize,
+}
+
+// a statement placed in memory
+struct Item {
+    section: usize,
+    addr: u16,
+    statement: Statement,
+}
+
+/// Assemble a source file; `file` is only used to label errors
+pub fn assemble(file: &str, source: &str) -> Result<Assembly, AsmErrors> {
+    let mut errors = Vec::new();
+    let mut assembly = Assembly::default();
+    let mut items = Vec::new();
+
+    // first pass: lay out memory and collect labels
+    let mut section: Option<usize> = None;
+    // with the lines they were declared on
+    let mut imports: Vec<(Token, usize)> = Vec::new();
+    let mut exports: Vec<(Token, usize)> = Vec::new();
+    let mut pc: u32 = 0;
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let statement = match parse_line(line, text) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        let (label, statement) = statement;
+
+        // a label on a .ORIG line names the new origin, so it is defined further down
+        let on_orig = statement.as_ref().is_some_and(|s| s.name == ".ORIG");
+        if let Some(label) = label.as_ref().filter(|_| !on_orig) {
+            let pc = section.map(|_| pc);
+            if let Err(e) = define_label(&mut assembly.symbols, label, line, pc) {
+                errors.push(e);
+            }
+        }
+
+        let Some(statement) = statement else {
+            continue;
+        };
+        match statement.name.as_str() {
+            ".ORIG" => {
+                let mut operands = Operands::new(&statement);
+                match operands
+                    .number(0, 0xFFFF)
+                    .and_then(|origin| operands.finish().map(|_| origin))
+                {
+                    Ok(origin) => {
+                        pc = origin as u32;
+                        assembly.segments.push(Segment {
+                            origin: origin as u16,
+                            words: Vec::new(),
+                        });
+                        section = Some(assembly.segments.len() - 1);
+                        if let Some(label) = &label {
+                            if let Err(e) =
+                                define_label(&mut assembly.symbols, label, line, Some(pc))
+                            {
+                                errors.push(e);
+                            }
+                        }
+                    }
+                    Err(e) => errors.push(e),
+                }
+                continue;
+            }
+            ".END" => {
+                section = None;
+                continue;
+            }
+            ".IMPORT" | ".EXPORT" => {
+                let mut operands = Operands::new(&statement);
+                match operands
+                    .label()
+                    .and_then(|label| operands.finish().map(|_| label))
+                {
+                    Ok(label) if statement.name == ".IMPORT" => imports.push((label.clone(), line)),
+                    Ok(label) => exports.push((label.clone(), line)),
+                    Err(e) => errors.push(e),
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let Some(current) = section else {
+            errors.push(error(
+                AsmErrorKind::OutsideOrig,
+                statement.mnemonic.pos(line),
+            ));
+            continue;
+        };
+        let size = match statement_size(&statement) {
+            Ok(size) => size,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+
+        let addr = pc;
+        pc += size;
+        if pc > 0x10000 {
+            errors.push(error(
+                AsmErrorKind::MemoryOverflow,
+                statement.mnemonic.pos(line),
+            ));
+            section = None;
+            continue;
+        }
+        items.push(Item {
+            section: current,
+            addr: addr as u16,
+            statement,
+        });
+    }
+
+    for (tokens, list, imported) in [
+        (&imports, &mut assembly.imports, true),
+        (&exports, &mut assembly.exports, false),
+    ] {
+        for (token, line) in tokens {
+            let line = *line;
+            let defined = assembly.symbols.get(&token.text).is_some();
+            if list.contains(&token.text) {
+                continue;
+            } else if imported && defined {
+                // a symbol can't be both here and in another module
+                errors.push(error(AsmErrorKind::DuplicateLabel, token.pos(line)));
+            } else if !imported && !defined {
+                errors.push(error(AsmErrorKind::UndefinedLabel, token.pos(line)));
+            }
+            list.push(token.text.clone());
+        }
+    }
+
+    // second pass: encode, now that every label is known
+    let mut refs = References {
+        symbols: &assembly.symbols,
+        imports: &assembly.imports,
+        relocations: Vec::new(),
+        fixups: Vec::new(),
+    };
+    for item in &items {
+        match encode(&item.statement, item.addr, &mut refs) {
+            Ok(words) => {
+                // `..` would overflow after a word at xFFFF
+                for (addr, _) in (item.addr..=0xFFFF).zip(&words) {
+                    assembly.source_map.insert(addr, item.statement.line);
+                }
+                assembly.segments[item.section].words.extend(words);
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    (assembly.relocations, assembly.fixups) = (refs.relocations, refs.fixups);
+
+    if errors.is_empty() {
+        Ok(assembly)
+    } else {
+        for e in &mut errors {
+            e.file = file.to_string();
+        }
+        errors.sort_by_key(|e| (e.pos.line, e.pos.col));
+        Err(AsmErrors(errors))
+    }
+}
+
+// define a label at `pc`, which is None outside of any .ORIG block
+fn define_label(
+    symbols: &mut SymbolTable,
+    label: &Token,
+    line: usize,
+    pc: Option<u32>,
+) -> Result<(), AsmError> {
+    let name = label.text.trim_end_matches(':');
+    match pc {
+        None => Err(error(AsmErrorKind::OutsideOrig, label.pos(line))),
+        Some(_) if symbols.get(name).is_some() => {
+            Err(error(AsmErrorKind::DuplicateLabel, label.pos(line)))
+        }
+        // just past the last word of memory, which would wrap around to x0000
+        Some(0x10000..) => Err(error(AsmErrorKind::MemoryOverflow, label.pos(line))),
+        Some(pc) => {
+            symbols.insert(name, pc as u16);
+            Ok(())
+        }
+    }
+}
+
+// split a line into its label and statement
+fn parse_line(line: usize, text: &str) -> Result<(Option<Token>, Option<Statement>), AsmError> {
+    let mut tokens = lexer::tokenize(text)
+        .map_err(|(kind, col, token)| error(kind, Pos { line, col, token }))?;
+    let end_col = text.chars().count() + 1;
+
+    let mut label = None;
+    if let Some(first) = tokens.first() {
+        if first.kind == TokenKind::Word && !is_mnemonic(&first.text) {
+            let first = tokens.remove(0);
+            if !is_valid_label(first.text.trim_end_matches(':')) {
+                return Err(error(AsmErrorKind::InvalidLabel, first.pos(line)));
+            }
+            label = Some(first);
+        }
+    }
+
+    if tokens.is_empty() {
+        return Ok((label, None));
+    }
+    let mnemonic = tokens.remove(0);
+    let name = mnemonic.text.to_ascii_uppercase();
+    // `FOO R1` is more likely a misspelt instruction than a label followed by garbage
+    if let Some(label) = &label {
+        let operand_like = mnemonic.kind != TokenKind::Directive
+            && (register_number(&mnemonic.text).is_some() || mnemonic.kind != TokenKind::Word);
+        if operand_like {
+            return Err(error(AsmErrorKind::UnknownInstruction, label.pos(line)));
+        }
+    }
+    let known = match mnemonic.kind {
+        TokenKind::Word => is_mnemonic(&name),
+        TokenKind::Directive => DIRECTIVES.contains(&name.as_str()),
+        _ => false,
+    };
+    if !known {
+        return Err(error(AsmErrorKind::UnknownInstruction, mnemonic.pos(line)));
+    }
+
+    Ok((
+        label,
+        Some(Statement {
+            line,
+            name,
+            mnemonic,
+            operands: tokens,
+            end_col,
+        }),
+    ))
+}
+
+const DIRECTIVES: &[&str] = &[
+    ".ORIG", ".FILL", ".BLKW", ".STRINGZ", ".END", ".EXPORT", ".IMPORT",
+];
+
+const INSTRUCTIONS: &[&str] = &[
+    "ADD", "AND", "NOT", "LD", "LDI", "LDR", "LEA", "ST", "STI", "STR", "JMP", "RET", "JSR",
+    "JSRR", "RTI", "TRAP",
+];
+
+const TRAP_ALIASES: &[(&str, u16)] = &[
+    ("GETC", 0x20),
+    ("OUT", 0x21),
+    ("PUTS", 0x22),
+    ("IN", 0x23),
+    ("PUTSP", 0x24),
+    ("HALT", 0x25),
+];
+
+// condition flags of a BR mnemonic, e.g. BRnz; plain BR means BRnzp
+fn branch_flags(name: &str) -> Option<u16> {
+    let flags = name.to_ascii_uppercase().strip_prefix("BR")?.to_string();
+    if flags.is_empty() {
+        return Some(0b111);
+    }
+
+    // flags must come in n, z, p order, each at most once
+    let mut bits = 0;
+    let mut last = 3;
+    for c in flags.chars() {
+        let (bit, order) = match c {
+            'N' => (0b100, 0),
+            'Z' => (0b010, 1),
+            'P' => (0b001, 2),
+            _ => return None,
+        };
+        if last != 3 && order <= last {
+            return None;
+        }
+        last = order;
+        bits |= bit;
+    }
+    Some(bits)
+}
+
+fn is_mnemonic(text: &str) -> bool {
+    let name = text.to_ascii_uppercase();
+    INSTRUCTIONS.contains(&name.as_str())
+        || TRAP_ALIASES.iter().any(|&(alias, _)| alias == name)
+        || branch_flags(&name).is_some()
+}
+
+fn register_number(text: &str) -> Option<u16> {
+    match text.as_bytes() {
+        [b'R' | b'r', n @ b'0'..=b'7'] => Some((n - b'0') as u16),
+        _ => None,
+    }
+}
+
+fn is_valid_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && register_number(name).is_none()
+}
+
+// number of words a statement takes up
+fn statement_size(statement: &Statement) -> Result<u32, AsmError> {
+    let mut operands = Operands::new(statement);
+    let size = match statement.name.as_str() {
+        ".BLKW" => operands.number(0, 0xFFFF)? as u32,
+        ".STRINGZ" => operands.string()?.len() as u32 + 1,
+        _ => return Ok(1),
+    };
+    operands.finish()?;
+    Ok(size)
+}
+
+// labels, and the references to them that will need patching if the program is moved or linked
+struct References<'a> {
+    symbols: &'a SymbolTable,
+    imports: &'a [String],
+    relocations: Vec<u16>,
+    fixups: Vec<Fixup>,
+}
+
+// encode a statement placed at `addr`
+fn encode(statement: &Statement, addr: u16, refs: &mut References) -> Result<Vec<u16>, AsmError> {
+    let mut ops = Operands::new(statement);
+    let name = statement.name.as_str();
+
+    let opcode = |op: OpCode| (op as u16) << 12;
+
+    let words = match name {
+        ".FILL" => vec![ops.value(addr, refs)?],
+        ".BLKW" => vec![0; ops.number(0, 0xFFFF)? as usize],
+        ".STRINGZ" => {
+            let mut words: Vec<u16> = ops.string()?.bytes().map(u16::from).collect();
+            words.push(0);
+            words
+        }
+        "ADD" | "AND" => {
+            let op = if name == "ADD" {
+                OpCode::ADD
+            } else {
+                OpCode::AND
+            };
+            let dr = ops.register()?;
+            let sr1 = ops.register()?;
+            let last = if ops.peek_register() {
+                ops.register()?
+            } else if ops.at_end() {
+                return Err(ops.missing("a register or immediate value"));
+            } else {
+                // immediate mode
+                (1 << 5) | ops.signed(5)?
+            };
+            vec![opcode(op) | dr << 9 | sr1 << 6 | last]
+        }
+        "NOT" => {
+            let dr = ops.register()?;
+            let sr = ops.register()?;
+            vec![opcode(OpCode::NOT) | dr << 9 | sr << 6 | 0x3F]
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            let op = match name {
+                "LD" => OpCode::LD,
+                "LDI" => OpCode::LDI,
+                "LEA" => OpCode::LEA,
+                "ST" => OpCode::ST,
+                _ => OpCode::STI,
+            };
+            let r = ops.register()?;
+            vec![opcode(op) | r << 9 | ops.pc_offset(FixupKind::PcOffset9, addr, refs)?]
+        }
+        "LDR" | "STR" => {
+            let op = if name == "LDR" {
+                OpCode::LDR
+            } else {
+                OpCode::STR
+            };
+            let r = ops.register()?;
+            let base = ops.register()?;
+            vec![opcode(op) | r << 9 | base << 6 | ops.signed(6)?]
+        }
+        "JMP" => vec![opcode(OpCode::JMP) | ops.register()? << 6],
+        "RET" => vec![opcode(OpCode::JMP) | 7 << 6],
+        "JSR" => {
+            vec![opcode(OpCode::JSR) | 1 << 11 | ops.pc_offset(FixupKind::PcOffset11, addr, refs)?]
+        }
+        "JSRR" => vec![opcode(OpCode::JSR) | ops.register()? << 6],
+        "RTI" => vec![opcode(OpCode::RTI)],
+        "TRAP" => vec![opcode(OpCode::TRAP) | ops.number(0, 0xFF)? as u16],
+        _ => {
+            if let Some(&(_, vector)) = TRAP_ALIASES.iter().find(|&&(alias, _)| alias == name) {
+                vec![opcode(OpCode::TRAP) | vector]
+            } else {
+                // only branches are left, as the mnemonic was checked when parsing
+                let flags = branch_flags(name).unwrap_or(0b111);
+                vec![
+                    opcode(OpCode::BR)
+                        | flags << 9
+                        | ops.pc_offset(FixupKind::PcOffset9, addr, refs)?,
+                ]
+            }
+        }
+    };
+
+    ops.finish()?;
+    Ok(words)
+}
+
+////////////////
+// operands
+////////////////
+
+// cursor over the operands of a statement
+struct Operands<'a> {
+    statement: &'a Statement,
+    idx: usize,
+}
+
+impl<'a> Operands<'a> {
+    fn new(statement: &'a Statement) -> Operands<'a> {
+        Operands { statement, idx: 0 }
+    }
+
+    fn next(&mut self, expected: &'static str) -> Result<&'a Token, AsmError> {
+        match self.statement.operands.get(self.idx) {
+            Some(token) => {
+                self.idx += 1;
+                Ok(token)
+            }
+            None => Err(self.missing(expected)),
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.idx >= self.statement.operands.len()
+    }
+
+    // error for an operand missing at the end of the line
+    fn missing(&self, expected: &'static str) -> AsmError {
+        error(
+            AsmErrorKind::Expected(expected),
+            Pos {
+                line: self.statement.line,
+                col: self.statement.end_col,
+                token: String::new(),
+            },
+        )
+    }
+
+    fn pos(&self, token: &Token) -> Pos {
+        token.pos(self.statement.line)
+    }
+
+    fn peek_register(&self) -> bool {
+        self.statement
+            .operands
+            .get(self.idx)
+            .is_some_and(|t| register_number(&t.text).is_some())
+    }
+
+    fn register(&mut self) -> Result<u16, AsmError> {
+        let token = self.next("a register")?;
+        register_number(&token.text)
+            .ok_or_else(|| error(AsmErrorKind::Expected("a register"), self.pos(token)))
+    }
+
+    /// A number between `min` and `max`
+    fn number(&mut self, min: i32, max: i32) -> Result<i32, AsmError> {
+        let token = self.next("a number")?;
+        let TokenKind::Number(n) = token.kind else {
+            return Err(error(AsmErrorKind::Expected("a number"), self.pos(token)));
+        };
+        if n < min || n > max {
+            return Err(error(
+                AsmErrorKind::OutOfRange { min, max },
+                self.pos(token),
+            ));
+        }
+        Ok(n)
+    }
+
+    /// A signed number fitting in `bits` bits, as a bit field
+    fn signed(&mut self, bits: u32) -> Result<u16, AsmError> {
+        let (min, max) = signed_range(bits);
+        let n = self.number(min, max)?;
+        Ok(n as u16 & ((1 << bits) - 1))
+    }
+
+    /// A label (or a literal offset), as a PC-relative bit field of the instruction at `addr`
+    fn pc_offset(
+        &mut self,
+        kind: FixupKind,
+        addr: u16,
+        refs: &mut References,
+    ) -> Result<u16, AsmError> {
+        let bits = if kind == FixupKind::PcOffset11 { 11 } else { 9 };
+        let token = self.next("a label or offset")?;
+        let offset = match token.kind {
+            TokenKind::Number(n) => n,
+            TokenKind::Word if refs.imports.contains(&token.text) => {
+                // left for the linker
+                refs.fixups.push(Fixup {
+                    addr,
+                    kind,
+                    symbol: token.text.clone(),
+                });
+                0
+            }
+            TokenKind::Word => {
+                let target = refs
+                    .symbols
+                    .get(&token.text)
+                    .ok_or_else(|| error(AsmErrorKind::UndefinedLabel, self.pos(token)))?;
+                // PC-relative offsets count from the next instruction
+                target as i32 - (addr as i32 + 1)
+            }
+            _ => {
+                return Err(error(
+                    AsmErrorKind::Expected("a label or offset"),
+                    self.pos(token),
+                ))
+            }
+        };
+
+        let (min, max) = signed_range(bits);
+        if offset < min || offset > max {
+            return Err(error(
+                AsmErrorKind::OutOfRange { min, max },
+                self.pos(token),
+            ));
+        }
+        Ok(offset as u16 & ((1 << bits) - 1))
+    }
+
+    /// A full word at `addr`: a number, or the address of a label
+    fn value(&mut self, addr: u16, refs: &mut References) -> Result<u16, AsmError> {
+        let token = self.next("a number or label")?;
+        match token.kind {
+            TokenKind::Number(n) => {
+                if !(-0x8000..=0xFFFF).contains(&n) {
+                    return Err(error(
+                        AsmErrorKind::OutOfRange {
+                            min: -0x8000,
+                            max: 0xFFFF,
+                        },
+                        self.pos(token),
+                    ));
+                }
+                Ok(n as u16)
+            }
+            TokenKind::Word if refs.imports.contains(&token.text) => {
+                refs.fixups.push(Fixup {
+                    addr,
+                    kind: FixupKind::Word,
+                    symbol: token.text.clone(),
+                });
+                Ok(0)
+            }
+            TokenKind::Word => {
+                let target = refs
+                    .symbols
+                    .get(&token.text)
+                    .ok_or_else(|| error(AsmErrorKind::UndefinedLabel, self.pos(token)))?;
+                refs.relocations.push(addr);
+                Ok(target)
+            }
+            _ => Err(error(
+                AsmErrorKind::Expected("a number or label"),
+                self.pos(token),
+            )),
+        }
+    }
+
+    /// A label name
+    fn label(&mut self) -> Result<&'a Token, AsmError> {
+        let token = self.next("a label")?;
+        if token.kind == TokenKind::Word && is_valid_label(&token.text) {
+            Ok(token)
+        } else {
+            Err(error(AsmErrorKind::Expected("a label"), self.pos(token)))
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a str, AsmError> {
+        let token = self.next("a string")?;
+        match &token.kind {
+            TokenKind::Str(s) => Ok(s),
+            _ => Err(error(AsmErrorKind::Expected("a string"), self.pos(token))),
+        }
+    }
+
+    /// Check that every operand was used
+    fn finish(&self) -> Result<(), AsmError> {
+        match self.statement.operands.get(self.idx) {
+            Some(token) => Err(error(AsmErrorKind::ExtraOperand, self.pos(token))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn signed_range(bits: u32) -> (i32, i32) {
+    (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+}
+
+#[cfg(test)]
+// instruction words are grouped by field
+#[allow(clippy::unusual_byte_groupings)]
+mod tests {
+    use super::*;
+
+    // words of a single line assembled at x3000
+    fn encode_line(line: &str) -> Vec<u16> {
+        let source = format!(".ORIG x3000\n{}\n.END\n", line);
+        match assemble("t.asm", &source) {
+            Ok(assembly) => assembly.segments[0].words.clone(),
+            Err(e) => panic!("`{}` did not assemble: {}", line, e),
+        }
+    }
+
+    // the first error for a source file
+    fn first_error(source: &str) -> String {
+        match assemble("t.asm", source) {
+            Ok(_) => panic!("assembled without errors:\n{}", source),
+            Err(e) => e.0[0].to_string(),
+        }
+    }
+
+    #[test]
+    fn encodes_every_mnemonic() {
+        let cases: &[(&str, u16)] = &[
+            ("ADD R1, R2, R3", 0b0001_001_010_000_011),
+            ("ADD R1, R2, #-1", 0b0001_001_010_1_11111),
+            ("add r1, r2, #15", 0b0001_001_010_1_01111),
+            ("AND R7, R6, R5", 0b0101_111_110_000_101),
+            ("AND R0, R0, #0", 0b0101_000_000_1_00000),
+            ("NOT R3, R4", 0b1001_011_100_111111),
+            ("LD R2, #-256", 0b0010_010_100000000),
+            ("LDI R3, #255", 0b1010_011_011111111),
+            ("LDR R4, R5, #-32", 0b0110_100_101_100000),
+            ("LEA R0, #0", 0b1110_000_000000000),
+            ("ST R1, #1", 0b0011_001_000000001),
+            ("STI R6, #-1", 0b1011_110_111111111),
+            ("STR R7, R6, #31", 0b0111_111_110_011111),
+            ("JMP R3", 0b1100_000_011_000000),
+            ("RET", 0b1100_000_111_000000),
+            ("JSR #-1024", 0b0100_1_10000000000),
+            ("JSR #1023", 0b0100_1_01111111111),
+            ("JSRR R4", 0b0100_0_00_100_000000),
+            ("RTI", 0b1000_000000000000),
+            ("TRAP x25", 0xF025),
+            ("GETC", 0xF020),
+            ("OUT", 0xF021),
+            ("PUTS", 0xF022),
+            ("IN", 0xF023),
+            ("PUTSP", 0xF024),
+            ("HALT", 0xF025),
+            ("BR #0", 0b0000_111_000000000),
+            ("BRn #0", 0b0000_100_000000000),
+            ("BRz #0", 0b0000_010_000000000),
+            ("BRp #0", 0b0000_001_000000000),
+            ("BRnz #0", 0b0000_110_000000000),
+            ("BRzp #0", 0b0000_011_000000000),
+            ("BRnp #0", 0b0000_101_000000000),
+            ("brnzp #-1", 0b0000_111_111111111),
+            (".FILL x1234", 0x1234),
+            (".FILL 0x10", 0x10),
+            (".FILL #-1", 0xFFFF),
+            (".FILL b1010", 0b1010),
+            (".FILL 12", 12),
+        ];
+        for &(line, word) in cases {
+            assert_eq!(encode_line(line), [word], "{}", line);
+        }
+    }
+
+    #[test]
+    fn encodes_data() {
+        assert_eq!(encode_line(".BLKW 3"), [0, 0, 0]);
+        assert_eq!(encode_line(".STRINGZ \"a\\n\""), [0x61, 0x0A, 0]);
+        assert_eq!(encode_line(".STRINGZ \"\""), [0]);
+    }
+
+    #[test]
+    fn resolves_labels() {
+        let source = "\
+        .ORIG x3000
+LOOP    BRnzp LOOP
+        LD R0, DATA
+        JSR LOOP
+DATA    .FILL LOOP
+        .END
+";
+        let assembly = assemble("t.asm", source).unwrap();
+        assert_eq!(
+            assembly.segments[0].words,
+            [0x0FFF, 0b0010_000_000000001, 0b0100_1_11111111101, 0x3000]
+        );
+        assert_eq!(assembly.symbols.get("LOOP"), Some(0x3000));
+        assert_eq!(assembly.symbols.get("DATA"), Some(0x3003));
+        // only the .FILL holds an absolute address
+        assert_eq!(assembly.relocations, [0x3003]);
+        assert_eq!(assembly.source_map.get(&0x3001), Some(&3));
+    }
+
+    #[test]
+    fn checks_pc_offset_range() {
+        // x3000 reaches x3001 - 256 to x3001 + 255
+        let source = |target: u16| {
+            format!(
+                ".ORIG x2E00\n.BLKW {}\nLD R0, FAR\n.BLKW {}\nFAR .FILL 0\n.END\n",
+                0x3000 - 0x2E00,
+                target - 0x3001,
+            )
+        };
+        assert!(assemble("t.asm", &source(0x3001 + 255)).is_ok());
+        assert_eq!(
+            first_error(&source(0x3001 + 256)),
+            "t.asm:3:8: `FAR` is out of range (-256 to 255)"
+        );
+        // and backwards, x30FF reaches x3000
+        assert!(assemble("t.asm", ".ORIG x3000\nNEAR .BLKW 255\nBR NEAR\n.END\n").is_ok());
+        assert_eq!(
+            first_error(".ORIG x3000\nNEAR .BLKW 256\nBR NEAR\n.END\n"),
+            "t.asm:3:4: `NEAR` is out of range (-256 to 255)"
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_positions() {
+        let cases: &[(&str, &str)] = &[
+            (
+                "ADD R1, R2, #16",
+                "t.asm:2:13: `#16` is out of range (-16 to 15)",
+            ),
+            (
+                "ADD R1, R2",
+                "t.asm:2:11: expected a register or immediate value, found end of line",
+            ),
+            (
+                "ADD R1, x3000, R2",
+                "t.asm:2:9: expected a register, found `x3000`",
+            ),
+            ("LD R0, NOPE", "t.asm:2:8: undefined label `NOPE`"),
+            ("ADD R1, R2, #1x", "t.asm:2:13: invalid number `#1x`"),
+            (
+                "  .STRINGZ \"abc",
+                "t.asm:2:12: unterminated string `\"abc`",
+            ),
+            (
+                ".STRINGZ \"\\q\"",
+                "t.asm:2:10: invalid escape sequence in `\"\\q\"`",
+            ),
+            ("RET R1", "t.asm:2:5: unexpected operand `R1`"),
+            (
+                "FOO R1",
+                "t.asm:2:1: unknown instruction or directive `FOO`",
+            ),
+            (
+                ".WORD 1",
+                "t.asm:2:1: unknown instruction or directive `.WORD`",
+            ),
+            (
+                "1LABEL ADD R0, R0, R0",
+                "t.asm:2:1: invalid number `1LABEL`",
+            ),
+            ("A-B ADD R0, R0, R0", "t.asm:2:1: unexpected `A-B`"),
+        ];
+        for &(line, message) in cases {
+            let source = format!(".ORIG x3000\n{}\n.END\n", line);
+            assert_eq!(first_error(&source), message, "{}", line);
+        }
+    }
+
+    #[test]
+    fn reports_layout_errors() {
+        assert_eq!(
+            first_error("ADD R0, R0, R0\n"),
+            "t.asm:1:1: `ADD` is outside of any .ORIG block"
+        );
+        assert_eq!(
+            first_error(".ORIG x3000\nA HALT\nA HALT\n.END\n"),
+            "t.asm:3:1: label `A` is already defined"
+        );
+        assert_eq!(
+            first_error(".ORIG xFFFF\n.BLKW 2\n.END\n"),
+            "t.asm:2:1: `.BLKW` runs past the end of memory"
+        );
+        // every error is reported, in order
+        let errors = assemble("t.asm", ".ORIG x3000\nLD R0, X\nADD R0\n.END\n").unwrap_err();
+        assert_eq!(errors.0.len(), 2);
+        assert_eq!(errors.0[0].pos.line, 2);
+        assert_eq!(errors.0[1].pos.line, 3);
+    }
+
+    #[test]
+    fn words_that_read_as_numbers_are_numbers() {
+        // `xAB` and `b10` are numbers, so they can't be labels
+        assert_eq!(
+            first_error(".ORIG x3000\nxAB ADD R0, R0, #1\n.END\n"),
+            "t.asm:2:1: unknown instruction or directive `xAB`"
+        );
+        assert_eq!(encode_line("LD R0, xAB"), [0b0010_000_010101011]);
+        assert_eq!(encode_line(".FILL b10"), [2]);
+        // but not every word starting with x or b
+        let assembly = assemble("t.asm", ".ORIG x3000\nxyz .FILL bad\nbad HALT\n.END\n").unwrap();
+        assert_eq!(assembly.symbols.get("xyz"), Some(0x3000));
+        assert_eq!(assembly.segments[0].words, [0x3001, 0xF025]);
+    }
+
+    #[test]
+    fn label_on_orig_names_the_origin() {
+        let source = "\
+MAIN    .ORIG x3000
+        HALT
+        .END
+SECOND  .ORIG x4000
+        HALT
+        .END
+";
+        let assembly = assemble("t.asm", source).unwrap();
+        assert_eq!(assembly.symbols.get("MAIN"), Some(0x3000));
+        assert_eq!(assembly.symbols.get("SECOND"), Some(0x4000));
+    }
+
+    #[test]
+    fn label_past_the_end_of_memory() {
+        let assembly = assemble("t.asm", ".ORIG xFFFE\n.FILL 0\nLAST .FILL 0\n.END\n").unwrap();
+        assert_eq!(assembly.symbols.get("LAST"), Some(0xFFFF));
+        // a label after the last word would wrap around to x0000
+        assert_eq!(
+            first_error(".ORIG xFFFF\n.FILL 0\nAFTER\n.END\n"),
+            "t.asm:3:1: `AFTER` runs past the end of memory"
+        );
+    }
+}
@@ -0,0 +1,435 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// debugger expressions
+//////////////////////////////
+
+use crate::vm::{number, SymbolTable, VM};
+use std::fmt;
+
+// NOTE
+// Expressions work on 16-bit words, like the machine: arithmetic wraps around, and comparisons
+// are signed (so `R0 < 0` works as expected). Conditions are true when they are not zero.
+//
+// Operators, loosest first (bitwise operators bind tighter than comparisons, like in Rust):
+//
+//  ||
+//  &&
+//  == != < <= > >=
+//  |
+//  &
+//  + -
+//  ! - ~   (unary)
+//
+// Operands are numbers (x41, #-5, 12, b101), characters ('A'), registers (R0-R7, PC, PSR, USP, SSP),
+// condition flags (N, Z, P), memory (mem[ADDR]), labels (their address), and parenthesized
+// expressions. Labels are looked up when the expression is parsed.
+
+/// Names of the registers, by index (see `Registers::get_reg`)
+pub const REGISTER_NAMES: [&str; 12] = [
+    "R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "PC", "PSR", "USP", "SSP",
+];
+
+/// An expression over registers, flags and memory, like `R0 == x41 && mem[x4000] > 3`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    text: String,
+    node: Node,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Num(u16),
+    Reg(u16),
+    // mask of the flag in PSR
+    Flag(u16),
+    Mem(Box<Node>),
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+impl Expr {
+    /// Parse an expression, resolving labels with `symbols`
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+            symbols,
+        };
+        let node = parser.expr(0)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected `{}`", token));
+        }
+        Ok(Expr {
+            text: text.trim().to_string(),
+            node,
+        })
+    }
+
+    pub fn eval(&self, vm: &VM) -> u16 {
+        eval(&self.node, vm)
+    }
+
+    /// Whether the expression is true (not zero)
+    pub fn holds(&self, vm: &VM) -> bool {
+        self.eval(vm) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Index of a register by name (case-insensitive)
+pub fn register_index(name: &str) -> Option<u16> {
+    REGISTER_NAMES
+        .iter()
+        .position(|r| r.eq_ignore_ascii_case(name))
+        .map(|idx| idx as u16)
+}
+
+fn eval(node: &Node, vm: &VM) -> u16 {
+    let registers = vm.registers();
+    match node {
+        Node::Num(val) => *val,
+        // the parser only lets valid register indices through
+        Node::Reg(idx) => registers.get_reg(*idx).unwrap_or(0),
+        Node::Flag(mask) => (registers.cond() & mask != 0) as u16,
+        Node::Mem(addr) => vm.peek_mem(eval(addr, vm)),
+        Node::Unary(op, a) => {
+            let a = eval(a, vm);
+            match *op {
+                "!" => (a == 0) as u16,
+                "-" => a.wrapping_neg(),
+                _ => !a,
+            }
+        }
+        Node::Binary(op, a, b) => {
+            let (a, b) = (eval(a, vm), eval(b, vm));
+            let (sa, sb) = (a as i16, b as i16);
+            match *op {
+                "||" => (a != 0 || b != 0) as u16,
+                "&&" => (a != 0 && b != 0) as u16,
+                "==" => (a == b) as u16,
+                "!=" => (a != b) as u16,
+                "<" => (sa < sb) as u16,
+                "<=" => (sa <= sb) as u16,
+                ">" => (sa > sb) as u16,
+                ">=" => (sa >= sb) as u16,
+                "|" => a | b,
+                "&" => a & b,
+                "+" => a.wrapping_add(b),
+                _ => a.wrapping_sub(b),
+            }
+        }
+    }
+}
+
+////////////////
+// parsing
+////////////////
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    // number, register, flag or label
+    Word(String),
+    Char(u16),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Char(c) => write!(f, "'{}'", *c as u8 as char),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+// longest first, so that `<=` is not read as `<`
+const OPERATORS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "&", "+", "-", "!", "~", "(", ")", "[", "]",
+];
+
+// binary operators by precedence level, loosest first
+const LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<", "<=", ">", ">="],
+    &["|"],
+    &["&"],
+    &["+", "-"],
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '#' {
+            // `#-5` is a single number
+            let start = if rest.starts_with("#-") { 2 } else { 1 };
+            let len = rest[start..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .map_or(rest.len(), |i| i + start);
+            tokens.push(Token::Word(rest[..len].to_string()));
+            rest = &rest[len..];
+        } else if c == '\'' {
+            let mut chars = rest[1..].chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), Some('\'')) if c.is_ascii() => {
+                    tokens.push(Token::Char(c as u16));
+                    rest = &rest[3..];
+                }
+                _ => return Err("characters are written like 'A'".to_string()),
+            }
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(format!("unexpected `{}`", c));
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl Parser<'_> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(found)) if *found == op => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(token) => Err(format!("expected `{}`, found `{}`", op, token)),
+            None => Err(format!("expected `{}`", op)),
+        }
+    }
+
+    /// Binary operators from precedence `level` on
+    fn expr(&mut self, level: usize) -> Result<Node, String> {
+        let Some(ops) = LEVELS.get(level) else {
+            return self.unary();
+        };
+
+        let mut node = self.expr(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| ops.contains(op)) {
+            self.pos += 1;
+            let rhs = self.expr(level + 1)?;
+            node = Node::Binary(op, Box::new(node), Box::new(rhs));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.peek_op() {
+            Some(op @ ("!" | "-" | "~")) => {
+                self.pos += 1;
+                Ok(Node::Unary(op, Box::new(self.unary()?)))
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let Some(token) = self.tokens.get(self.pos).cloned() else {
+            return Err("expected a value".to_string());
+        };
+        self.pos += 1;
+
+        match token {
+            Token::Char(c) => Ok(Node::Num(c)),
+            Token::Op("(") => {
+                let node = self.expr(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Word(word)
+                if word.eq_ignore_ascii_case("mem") && self.peek_op() == Some("[") =>
+            {
+                self.pos += 1;
+                let addr = self.expr(0)?;
+                self.expect("]")?;
+                Ok(Node::Mem(Box::new(addr)))
+            }
+            Token::Word(word) => self.word(&word),
+            Token::Op(op) => Err(format!("expected a value, found `{}`", op)),
+        }
+    }
+
+    fn word(&self, word: &str) -> Result<Node, String> {
+        if let Some(idx) = register_index(word) {
+            return Ok(Node::Reg(idx));
+        }
+        // bits of the condition flags in PSR
+        match word {
+            "N" | "n" => return Ok(Node::Flag(1 << 2)),
+            "Z" | "z" => return Ok(Node::Flag(1 << 1)),
+            "P" | "p" => return Ok(Node::Flag(1 << 0)),
+            _ => {}
+        }
+        // like in assembly, bare digits are decimal
+        if let Some(val) = number::parse_word(word, 10) {
+            return Ok(Node::Num(val));
+        }
+
+        self.symbols
+            .get(word)
+            .or_else(|| {
+                self.symbols
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(word))
+                    .map(|(_, addr)| addr)
+            })
+            .map(Node::Num)
+            .ok_or_else(|| format!("no register, number or label called `{}`", word))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_console::TestConsole;
+
+    // evaluate with R0 = 5, R1 = -3, the P flag set, and ARR = x4000 holding 7, 'A', x4000
+    fn value(text: &str) -> u16 {
+        let mut console = TestConsole::default();
+        let mut vm = VM::new(&mut console);
+        let registers = vm.registers_mut();
+        registers.set_reg(0, 5).unwrap();
+        registers.set_reg(1, -3i16 as u16).unwrap();
+        registers.set_reg(9, 0b001).unwrap();
+        for (addr, val) in [(0x4000, 7), (0x4001, 'A' as u16), (0x4002, 0x4000)] {
+            vm.poke_mem(addr, val);
+        }
+        vm.symbols_mut().insert("ARR", 0x4000);
+
+        let expr = Expr::parse(text, vm.symbols())
+            .unwrap_or_else(|e| panic!("`{}` did not parse: {}", text, e));
+        expr.eval(&vm)
+    }
+
+    fn parse_error(text: &str) -> String {
+        Expr::parse(text, &SymbolTable::new()).unwrap_err()
+    }
+
+    #[test]
+    fn precedence() {
+        let cases = [
+            // bitwise operators bind looser than + and -, and tighter than comparisons
+            ("1 + 2 & 3", 3),
+            ("1 | 2 & 0", 1),
+            ("1 == 1 & 0", 0),
+            ("6 & 3 == 2", 1),
+            ("1 + 1 == 2", 1),
+            ("0 && 0 || 1", 1),
+            ("1 || 0 && 0", 1),
+            ("(1 || 0) && 0", 0),
+            ("2 - 1 - 1", 0),
+            ("-R0 + 5", 0),
+            ("!0 + 1", 2),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(value(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn signed_comparisons_and_wrapping() {
+        let cases = [
+            ("R1 < R0", 1),
+            ("R1 < 0", 1),
+            ("xFFFF < 1", 1),
+            ("x8000 <= x7FFF", 1),
+            ("R1 >= #-3", 1),
+            ("R1 > #-3", 0),
+            ("R1 == xFFFD", 1),
+            ("R0 != 5", 0),
+            ("x7FFF + 1", 0x8000),
+            ("0 - 1", 0xFFFF),
+            ("~0", 0xFFFF),
+            ("!R0", 0),
+            ("!!R0", 1),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(value(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn operands() {
+        let cases = [
+            ("#-5", 0xFFFB),
+            ("#-5 + 5", 0),
+            ("12", 12),
+            ("x41", 0x41),
+            ("0x10", 0x10),
+            ("b101", 5),
+            ("'A'", 65),
+            ("' '", 32),
+            ("r0", 5),
+            ("PSR", 1),
+            ("P", 1),
+            ("Z || N", 0),
+            ("ARR", 0x4000),
+            ("arr", 0x4000),
+            ("mem[ARR]", 7),
+            ("mem[ARR + 1] == 'A'", 1),
+            ("MEM[mem[x4002]]", 7),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(value(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_error("1 +"), "expected a value");
+        assert_eq!(parse_error("(1"), "expected `)`");
+        assert_eq!(parse_error("mem[1)"), "expected `]`, found `)`");
+        assert_eq!(parse_error("1 2"), "unexpected `2`");
+        assert_eq!(parse_error("R0 $ 1"), "unexpected `$`");
+        assert_eq!(parse_error("'ab'"), "characters are written like 'A'");
+        assert_eq!(
+            parse_error("NOPE"),
+            "no register, number or label called `NOPE`"
+        );
+        // too large for a word
+        assert_eq!(
+            parse_error("x10000"),
+            "no register, number or label called `x10000`"
+        );
+    }
+
+    #[test]
+    fn displays_as_written() {
+        let expr = Expr::parse("  R0 ==  x41 ", &SymbolTable::new()).unwrap();
+        assert_eq!(expr.to_string(), "R0 ==  x41");
+    }
+}
@@ -0,0 +1,725 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// gdb remote protocol
+//////////////////////////////
+
+use super::{Access, Debugger, Stop, WatchKind};
+use crate::vm::{terminal_io, Exception, VmError, MEM_SIZE, VM};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+// NOTE
+// This speaks the gdb remote serial protocol, so that a debugger can drive the LC-3 program.
+// See "Remote Protocol" in the gdb manual for the packets.
+// Stock gdb has no LC-3 architecture, and `TARGET_XML` cannot give it one (an `<architecture>`
+// element has to name one gdb knows), so this is for clients that take the registers from
+// `TARGET_XML` alone. It has not been tried with gdb itself.
+//
+// The protocol counts memory in bytes, but LC-3 memory is made of 16-bit words. Word `x` is shown
+// as the two bytes at `2 * x` (low byte first). PC is shown the same way (which is why it is 32
+// bits wide), so that it lines up with breakpoint addresses. The other registers hold plain
+// values, so a pointer in R0 is at byte `2 * R0`.
+
+/// Register layout, given to clients as `target.xml`
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+// register numbers, as in `TARGET_XML`
+const PC: usize = 8;
+const PSR: usize = 9;
+const NUM_REGS: usize = 10;
+
+// byte addresses in packets, two for every word
+const MEM_BYTES: u32 = 2 * MEM_SIZE as u32;
+
+// signals in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// How a gdb session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdbEnd {
+    /// gdb detached, and the program should go on by itself
+    Detached,
+    /// gdb killed the program, or the connection was lost
+    Killed,
+}
+
+impl Debugger {
+    /// Serve one gdb connection, reading packets from `input` and replying on `output`
+    ///
+    /// `input` is read from a separate thread, so that gdb can interrupt a running program.
+    pub fn serve_gdb(
+        &mut self,
+        vm: &mut VM,
+        input: impl Read + Send + 'static,
+        output: impl Write,
+    ) -> io::Result<GdbEnd> {
+        let mut conn = Connection::new(input, output);
+        let mut session = Session {
+            last_stop: format!("S{:02x}", SIGTRAP),
+            points: HashMap::new(),
+            messages: Vec::new(),
+        };
+
+        while let Some(packet) = conn.read_packet()? {
+            let reply = match packet.as_str() {
+                "k" | "vKill" => {
+                    conn.send("OK")?;
+                    return Ok(GdbEnd::Killed);
+                }
+                _ if packet.starts_with('D') => {
+                    conn.send("OK")?;
+                    return Ok(GdbEnd::Detached);
+                }
+                "QStartNoAckMode" => {
+                    conn.send("OK")?;
+                    conn.ack = false;
+                    continue;
+                }
+                _ => session.handle(self, vm, &packet),
+            };
+            // messages go to gdb's console before the stop reply
+            for msg in session.messages.drain(..) {
+                conn.send(&console_output(&msg))?;
+            }
+            conn.send(&reply)?;
+        }
+
+        Ok(GdbEnd::Killed)
+    }
+}
+
+////////////////
+// packets
+////////////////
+
+struct Session {
+    // reply to `?`
+    last_stop: String,
+    // debugger numbers of the breakpoints and watchpoints set by gdb, by their Z packet
+    points: HashMap<String, usize>,
+    // text for gdb to show, not sent yet
+    messages: Vec<String>,
+}
+
+impl Session {
+    /// Reply to a packet (an empty reply means it is not supported)
+    fn handle(&mut self, debugger: &mut Debugger, vm: &mut VM, packet: &str) -> String {
+        let Some((cmd, args)) = packet.split_at_checked(1) else {
+            return String::new();
+        };
+        let reply = match cmd {
+            "?" => Some(self.last_stop.clone()),
+            "q" => query(args),
+            "H" | "T" => Some("OK".to_string()),
+            "g" => Some((0..NUM_REGS).map(|n| read_reg(vm, n)).collect()),
+            "G" => write_regs(vm, args),
+            "p" => usize::from_str_radix(args, 16)
+                .ok()
+                .filter(|&n| n < NUM_REGS)
+                .map(|n| read_reg(vm, n)),
+            "P" => args
+                .split_once('=')
+                .and_then(|(n, val)| write_reg(vm, usize::from_str_radix(n, 16).ok()?, val)),
+            "m" => read_mem(vm, args),
+            "M" => write_mem(vm, args),
+            "Z" | "z" => self.set_point(debugger, cmd == "Z", args),
+            "c" | "s" => {
+                if !args.is_empty() {
+                    let Some(addr) = word_addr(args) else {
+                        return "E01".to_string();
+                    };
+                    vm.registers_mut().set_pc(addr);
+                }
+                let stop = match cmd {
+                    "c" => debugger.cont(vm),
+                    _ => debugger.step(vm, 1),
+                };
+                Some(self.stopped(debugger, vm, stop))
+            }
+            "b" => {
+                let stop = match args {
+                    "c" => debugger.reverse_cont(vm),
+                    "s" => debugger.reverse_step(vm, 1),
+                    _ => return String::new(),
+                };
+                Some(self.stopped(debugger, vm, stop))
+            }
+            _ => Some(String::new()),
+        };
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+
+    /// Insert (`Z`) or remove (`z`) a breakpoint or watchpoint
+    fn set_point(&mut self, debugger: &mut Debugger, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split([',', ';']);
+        let kind = fields.next()?;
+        let addr = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let len = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let last = addr
+            .checked_add(len.max(1) - 1)
+            .filter(|&byte| byte < MEM_BYTES)?;
+        let (start, end) = ((addr / 2) as u16, (last / 2) as u16);
+
+        let key = format!("{},{:x},{:x}", kind, addr, len);
+        if !insert {
+            if let Some(id) = self.points.remove(&key) {
+                debugger.delete(id);
+            }
+            return Some("OK".to_string());
+        }
+        if self.points.contains_key(&key) {
+            return Some("OK".to_string());
+        }
+
+        let id = match kind {
+            // software and hardware breakpoints are the same thing here
+            "0" | "1" => debugger.add_breakpoint(start),
+            "2" => debugger.add_watchpoint(start, end, WatchKind::Write, false),
+            "3" => debugger.add_watchpoint(start, end, WatchKind::Read, false),
+            "4" => debugger.add_watchpoint(start, end, WatchKind::Access, false),
+            _ => return Some(String::new()),
+        };
+        self.points.insert(key, id);
+        Some("OK".to_string())
+    }
+
+    /// Remember how the program stopped, and tell gdb
+    fn stopped(&mut self, debugger: &Debugger, vm: &VM, stop: Stop) -> String {
+        self.last_stop = match stop {
+            Stop::Done => format!("S{:02x}", SIGTRAP),
+            Stop::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::Watchpoint(hit) => {
+                let access = debugger
+                    .watchpoints()
+                    .iter()
+                    .any(|w| w.id == hit.id && w.kind == WatchKind::Access);
+                let kind = match hit.access {
+                    _ if access => "awatch",
+                    Access::Read { .. } => "rwatch",
+                    Access::Write { .. } => "watch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, 2 * hit.addr as u32)
+            }
+            Stop::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            Stop::Interrupted => format!("S{:02x}", SIGINT),
+            Stop::Halted => "W00".to_string(),
+            Stop::Error(e) => {
+                // the signal alone does not say what went wrong
+                let mut msg = format!("error: {}", e);
+                if let Some(label) = vm.symbolize(vm.last_pc()) {
+                    msg.push_str(&format!(" (in {})", label));
+                }
+                msg.push('\n');
+                self.messages.push(msg);
+                let signal = match e {
+                    VmError::UnhandledException {
+                        exception: Exception::AccessViolation,
+                        ..
+                    } => SIGSEGV,
+                    _ => SIGILL,
+                };
+                format!("S{:02x}", signal)
+            }
+        };
+        self.last_stop.clone()
+    }
+}
+
+/// Reply to a `q` packet
+fn query(args: &str) -> Option<String> {
+    if args.starts_with("Supported") {
+        return Some(
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+;\
+             ReverseStep+;ReverseContinue+"
+                .to_string(),
+        );
+    }
+    if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        let (offset, len) = range.split_once(',')?;
+        let offset = usize::from_str_radix(offset, 16).ok()?;
+        let len = usize::from_str_radix(len, 16).ok()?;
+        let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..)?;
+        return Some(if rest.len() > len {
+            format!("m{}", &rest[..len])
+        } else {
+            format!("l{}", rest)
+        });
+    }
+    Some(
+        match args {
+            "Attached" => "1",
+            "C" => "QC1",
+            "fThreadInfo" => "m1",
+            "sThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string(),
+    )
+}
+
+////////////////
+// registers and memory
+////////////////
+
+/// Register `n` as hex bytes, low byte first
+fn read_reg(vm: &VM, n: usize) -> String {
+    let registers = vm.registers();
+    match n {
+        PC => hex_le(2 * registers.pc() as u32, 4),
+        PSR => hex_le(registers.psr() as u32, 2),
+        _ => hex_le(registers.gprs()[n] as u32, 2),
+    }
+}
+
+fn write_reg(vm: &mut VM, n: usize, hex: &str) -> Option<String> {
+    let val = parse_le(hex)?;
+    let registers = vm.registers_mut();
+    match n {
+        PC if val < MEM_BYTES => registers.set_pc((val / 2) as u16),
+        0..=7 | PSR => registers.set_reg(n as u16, val as u16).ok()?,
+        _ => return None,
+    }
+    Some("OK".to_string())
+}
+
+fn write_regs(vm: &mut VM, mut hex: &str) -> Option<String> {
+    for n in 0..NUM_REGS {
+        let len = if n == PC { 8 } else { 4 };
+        let val = hex.get(..len)?;
+        hex = &hex[len..];
+        write_reg(vm, n, val)?;
+    }
+    Some("OK".to_string())
+}
+
+/// `m ADDR,LEN`
+fn read_mem(vm: &VM, args: &str) -> Option<String> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = u32::from_str_radix(addr, 16).ok()?;
+    let len = u32::from_str_radix(len, 16).ok()?;
+    if addr >= MEM_BYTES {
+        return None;
+    }
+    let bytes = (addr..addr.checked_add(len)?).take_while(|&byte| byte < MEM_BYTES);
+    Some(
+        bytes
+            .map(|byte| {
+                // peeking does not disturb devices, unlike a real read
+                let word = vm.peek_mem((byte / 2) as u16);
+                format!("{:02x}", (word >> (8 * (byte % 2))) as u8)
+            })
+            .collect(),
+    )
+}
+
+/// `M ADDR,LEN:DATA`
+fn write_mem(vm: &mut VM, args: &str) -> Option<String> {
+    let (range, data) = args.split_once(':')?;
+    let (addr, len) = range.split_once(',')?;
+    let addr = u32::from_str_radix(addr, 16).ok()?;
+    let len = u32::from_str_radix(len, 16).ok()?;
+    if addr.checked_add(len).is_none_or(|end| end > MEM_BYTES) {
+        return None;
+    }
+    // check all of the data before writing any of it
+    let bytes: Vec<u8> = data
+        .as_bytes()
+        .chunks(2)
+        .map(hex_byte)
+        .collect::<Option<_>>()?;
+    if bytes.len() != len as usize {
+        return None;
+    }
+    for (i, byte) in (addr..).zip(bytes) {
+        let shift = 8 * (i % 2);
+        let word = vm.peek_mem((i / 2) as u16) & !(0xFF << shift) | (byte as u16) << shift;
+        vm.poke_mem((i / 2) as u16, word);
+    }
+    Some("OK".to_string())
+}
+
+/// `O` packet showing `text` on gdb's console
+fn console_output(text: &str) -> String {
+    let hex: String = text.bytes().map(|b| format!("{:02x}", b)).collect();
+    format!("O{}", hex)
+}
+
+/// `val` as `bytes` hex bytes, low byte first
+fn hex_le(val: u32, bytes: usize) -> String {
+    (0..bytes)
+        .map(|i| format!("{:02x}", (val >> (8 * i)) as u8))
+        .collect()
+}
+
+/// Hex bytes, low byte first
+fn parse_le(hex: &str) -> Option<u32> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || hex.len() > 8 {
+        return None;
+    }
+    let mut val = 0;
+    for (i, byte) in hex.as_bytes().chunks(2).enumerate() {
+        val |= (hex_byte(byte)? as u32) << (8 * i);
+    }
+    Some(val)
+}
+
+/// A byte written as two hex digits
+fn hex_byte(digits: &[u8]) -> Option<u8> {
+    match digits {
+        [hi, lo] => Some((hex_digit(*hi)? << 4) | hex_digit(*lo)?),
+        _ => None,
+    }
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+/// The word at byte address `hex`
+fn word_addr(hex: &str) -> Option<u16> {
+    let addr = u32::from_str_radix(hex, 16).ok()?;
+    (addr < MEM_BYTES).then_some((addr / 2) as u16)
+}
+
+////////////////
+// connection
+////////////////
+
+struct Connection<W: Write> {
+    // bytes from gdb, read on another thread
+    input: Receiver<u8>,
+    output: W,
+    // whether packets are acknowledged with `+`
+    ack: bool,
+}
+
+impl<W: Write> Connection<W> {
+    fn new(mut input: impl Read + Send + 'static, output: W) -> Connection<W> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok(n @ 1..) = input.read(&mut buf) {
+                for &byte in &buf[..n] {
+                    // CTRL-C in gdb pauses the program
+                    if byte == 0x03 {
+                        terminal_io::interrupt();
+                    }
+                    if tx.send(byte).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Connection {
+            input: rx,
+            output,
+            ack: true,
+        }
+    }
+
+    /// Next packet from gdb, or None once it disconnects
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip acks, interrupts, and anything else between packets
+            loop {
+                match self.input.recv() {
+                    Ok(b'$') => break,
+                    Ok(_) => {}
+                    Err(_) => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.input.recv() {
+                    Ok(b'#') => break,
+                    Ok(byte) => data.push(byte),
+                    Err(_) => return Ok(None),
+                }
+            }
+            let (Ok(hi), Ok(lo)) = (self.input.recv(), self.input.recv()) else {
+                return Ok(None);
+            };
+
+            if self.ack {
+                if hex_byte(&[hi, lo]) != Some(checksum(&data)) {
+                    self.output.write_all(b"-")?;
+                    self.output.flush()?;
+                    continue;
+                }
+                self.output.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.output.write_all(packet.as_bytes())?;
+            self.output.flush()?;
+            if !self.ack {
+                return Ok(());
+            }
+            // resend until gdb acknowledges it
+            loop {
+                match self.input.recv() {
+                    Ok(b'+') => return Ok(()),
+                    Ok(b'-') => break,
+                    Ok(_) => {}
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::test_console::TestConsole;
+    use std::io::Cursor;
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+        ADD R0, R0, #1
+        ADD R0, R0, #1
+        ST R0, VAL
+        HALT
+    VAL .FILL 0
+        .END
+    ";
+
+    fn frame(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    // replies to `packets`, sent one by one (acknowledging each reply) to gdb serving `PROGRAM`
+    fn replies(packets: &[&str]) -> Vec<String> {
+        serve(packets.iter().map(|p| frame(p) + "+").collect())
+    }
+
+    // replies to the raw bytes `input`, from gdb serving `PROGRAM`
+    fn serve(input: String) -> Vec<String> {
+        let mut console = TestConsole::default();
+        let mut vm = VM::new(&mut console);
+        let assembly = asm::assemble("t.asm", PROGRAM).unwrap();
+        vm.load_assembly("t.asm", PROGRAM, &assembly).unwrap();
+
+        let mut output = Vec::new();
+        let end = Debugger::new()
+            .serve_gdb(&mut vm, Cursor::new(input.into_bytes()), &mut output)
+            .unwrap();
+        assert_eq!(end, GdbEnd::Killed);
+
+        String::from_utf8(output)
+            .unwrap()
+            .split('$')
+            .skip(1)
+            .map(|packet| packet.split_once('#').unwrap().0.to_string())
+            .collect()
+    }
+
+    // check each packet's reply, in one session
+    fn check(cases: &[(&str, &str)]) {
+        let packets: Vec<&str> = cases.iter().map(|(packet, _)| *packet).collect();
+        for ((packet, expected), reply) in cases.iter().zip(replies(&packets)) {
+            assert_eq!(reply, *expected, "reply to `{}`", packet);
+        }
+    }
+
+    #[test]
+    fn checksums_and_resends() {
+        let input = format!("$?#00{}-+", frame("?"));
+        let mut output = Vec::new();
+        let mut conn = Connection::new(Cursor::new(input.into_bytes()), &mut output);
+        // the first copy is refused, and the second one accepted
+        assert_eq!(conn.read_packet().unwrap().as_deref(), Some("?"));
+        // gdb refuses the reply once, so it is sent again
+        conn.send("S05").unwrap();
+        assert_eq!(conn.read_packet().unwrap(), None);
+        drop(conn);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("-+{0}{0}", frame("S05"))
+        );
+    }
+
+    #[test]
+    fn malformed_packets() {
+        check(&[
+            ("", ""),
+            ("\u{e9}", ""),
+            ("p", "E01"),
+            ("pa", "E01"),
+            ("pzz", "E01"),
+            ("P0=zz", "E01"),
+            ("P0=+f", "E01"),
+            ("P0", "E01"),
+            ("P8=00000200", "E01"),
+            ("G0000", "E01"),
+            ("m", "E01"),
+            ("m6000", "E01"),
+            ("mzz,2", "E01"),
+            ("M", "E01"),
+            ("M6000,2", "E01"),
+            ("Z0,6000", "E01"),
+            ("Z0,zz,2", "E01"),
+            ("Z9,6000,2", ""),
+            ("czz", "E01"),
+            ("bx", ""),
+        ]);
+    }
+
+    #[test]
+    fn registers() {
+        check(&[
+            ("p8", "00600000"),
+            ("p0", "0000"),
+            ("P0=3412", "OK"),
+            ("p0", "3412"),
+            ("P8=02600000", "OK"),
+            ("p8", "02600000"),
+            ("g", "34120000000000000000000000300000026000000200"),
+            ("G34127856000000000000000000300000046000000100", "OK"),
+            ("p1", "7856"),
+            ("p8", "04600000"),
+            ("p9", "0100"),
+        ]);
+    }
+
+    #[test]
+    fn memory() {
+        check(&[
+            ("m6000,4", "21102110"),
+            ("m6001,1", "10"),
+            ("m1fffe,10", "0000"),
+            ("m20000,2", "E01"),
+            ("m1,ffffffff", "E01"),
+            ("M6008,3:abcdef", "OK"),
+            ("m6008,4", "abcdef00"),
+            // nothing is written unless all of the data is good
+            ("M6000,2:00zz", "E01"),
+            ("M6000,2:00", "E01"),
+            ("M6000,1:+f", "E01"),
+            ("M1fffe,4:00000000", "E01"),
+            ("Mffffffff,ffffffff:00", "E01"),
+            ("Mffffffff,ffffffffffffffff:00", "E01"),
+            ("m6000,2", "2110"),
+        ]);
+    }
+
+    #[test]
+    fn out_of_range_points_and_jumps() {
+        check(&[
+            ("Z2,ffffffff,2", "E01"),
+            ("Z0,20000,1", "E01"),
+            ("Z2,1fffe,4", "E01"),
+            ("Z2,1fffe,2", "OK"),
+            ("cffffffff", "E01"),
+            ("s20000", "E01"),
+            ("p8", "00600000"),
+        ]);
+    }
+
+    #[test]
+    fn target_description() {
+        let xml = TARGET_XML;
+        check(&[
+            (
+                "qXfer:features:read:target.xml:0,10",
+                &format!("m{}", &xml[..0x10]),
+            ),
+            (
+                "qXfer:features:read:target.xml:10,ffff",
+                &format!("l{}", &xml[0x10..]),
+            ),
+            ("qXfer:features:read:target.xml:ffff,10", "l"),
+            ("qXfer:features:read:target.xml:0", "E01"),
+        ]);
+    }
+
+    #[test]
+    fn running_forwards_and_backwards() {
+        check(&[
+            ("Z0,6004,2", "OK"),
+            ("Z2,6008,2", "OK"),
+            ("c", "T05swbreak:;"),
+            ("p8", "04600000"),
+            ("p0", "0200"),
+            ("bs", "S05"),
+            ("p8", "02600000"),
+            ("p0", "0100"),
+            // stepping onto a breakpoint stops there like continuing would
+            ("s", "T05swbreak:;"),
+            ("c", "T05watch:6008;"),
+            ("m6008,2", "0200"),
+            ("bc", "T05watch:6008;"),
+            ("m6008,2", "0000"),
+            ("bc", "T05replaylog:begin;"),
+            ("p8", "00600000"),
+            ("z0,6004,2", "OK"),
+            ("z2,6008,2", "OK"),
+            ("c", "W00"),
+            ("?", "W00"),
+        ]);
+    }
+
+    #[test]
+    fn errors_are_shown_on_gdbs_console() {
+        // x3000 becomes the reserved opcode
+        // the console output is acknowledged too
+        let input = format!("{}+{}++{}+", frame("M6000,2:00d0"), frame("c"), frame("?"));
+        let out = serve(input);
+        let error = VmError::UnhandledException {
+            exception: Exception::IllegalOpcode,
+            addr: 0x3000,
+        };
+        assert_eq!(
+            out,
+            [
+                "OK".to_string(),
+                console_output(&format!("error: {}\n", error)),
+                "S04".to_string(),
+                "S04".to_string(),
+            ]
+        );
+        assert_eq!(console_output("hi\n"), "O68690a");
+    }
+}
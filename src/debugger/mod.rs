@@ -0,0 +1,917 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// debugger
+//////////////////////////////
+
+//! Breakpoints, watchpoints and run control on top of [`VM::run_until`],
+//! and a command interpreter and gdb remote protocol server for them.
+
+use crate::vm::{terminal_io, MemWrite, OpCode, Registers, StepOutcome, StopReason, VmError, VM};
+use std::collections::VecDeque;
+use std::fmt;
+
+mod expr;
+mod gdb;
+mod repl;
+
+pub use expr::Expr;
+pub use gdb::{GdbEnd, TARGET_XML};
+
+////////////////
+// breakpoints
+////////////////
+
+/// A place to stop before executing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// Number shown to the user, which stays the same as others are deleted
+    pub id: usize,
+    pub addr: u16,
+    pub enabled: bool,
+    /// Only stop if this holds
+    pub condition: Option<Expr>,
+    /// Number of upcoming hits to go past without stopping
+    pub ignore: usize,
+    /// Delete the breakpoint once it stops the program
+    pub temporary: bool,
+    /// Times the breakpoint was reached with its condition holding
+    pub hits: usize,
+}
+
+////////////////
+// watchpoints
+////////////////
+
+/// Kind of memory access a watchpoint looks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes
+    Access,
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read"),
+            WatchKind::Write => write!(f, "write"),
+            WatchKind::Access => write!(f, "access"),
+        }
+    }
+}
+
+/// Memory to keep an eye on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// Number shown to the user, shared with breakpoints
+    pub id: usize,
+    /// First address watched
+    pub start: u16,
+    /// Last address watched (included)
+    pub end: u16,
+    pub kind: WatchKind,
+    /// Keep going and log accesses for [`Debugger::take_logged`], instead of stopping
+    pub log: bool,
+    pub enabled: bool,
+}
+
+impl Breakpoint {
+    /// Whether the program is at this breakpoint, with its condition holding
+    fn reached(&self, vm: &VM) -> bool {
+        self.enabled
+            && self.addr == vm.registers().pc()
+            && self.condition.as_ref().is_none_or(|c| c.holds(vm))
+    }
+}
+
+impl Watchpoint {
+    fn matches(&self, addr: u16, write: bool) -> bool {
+        let kind = match (self.kind, write) {
+            (WatchKind::Access, _) => true,
+            (WatchKind::Write, write) => write,
+            (WatchKind::Read, write) => !write,
+        };
+        self.enabled && kind && (self.start..=self.end).contains(&addr)
+    }
+}
+
+/// An access caught by a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Watchpoint that caught it
+    pub id: usize,
+    /// Address of the instruction that made the access
+    pub pc: u16,
+    pub addr: u16,
+    pub access: Access,
+}
+
+/// A memory access, with the values involved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read { value: u16 },
+    Write { old: u16, new: u16 },
+}
+
+impl WatchHit {
+    /// Spell out the access, e.g. `x3001 (MAIN+1) wrote x4000 (ARRAY): x0000 -> x0005`
+    pub fn describe(&self, vm: &VM) -> String {
+        let at = |addr: u16| match vm.symbolize(addr) {
+            Some(label) => format!("x{:04X} ({})", addr, label),
+            None => format!("x{:04X}", addr),
+        };
+        match self.access {
+            Access::Read { value } => {
+                format!("{} read {}: x{:04X}", at(self.pc), at(self.addr), value)
+            }
+            Access::Write { old, new } => format!(
+                "{} wrote {}: x{:04X} -> x{:04X}",
+                at(self.pc),
+                at(self.addr),
+                old,
+                new
+            ),
+        }
+    }
+}
+
+////////////////
+// run control
+////////////////
+
+/// Why the debugger gave control back
+#[derive(Debug)]
+pub enum Stop {
+    /// The requested steps are done
+    Done,
+    /// About to execute an instruction with a breakpoint on it
+    Breakpoint(usize),
+    /// The last instruction made an access caught by a watchpoint
+    Watchpoint(WatchHit),
+    /// CTRL-C was pressed
+    Interrupted,
+    /// The clock was stopped, usually by HALT
+    Halted,
+    /// The VM ran into an error
+    Error(VmError),
+    /// Going backwards reached the oldest instruction still in the history
+    HistoryStart,
+}
+
+////////////////
+// history
+////////////////
+
+// NOTE
+// Every instruction run by the debugger leaves an entry in a journal, with the registers from
+// before it and the old value of each word it wrote. Going backwards pops entries and puts
+// those back. Only the machine state is restored: keys that were read stay read, and characters
+// that were displayed stay displayed.
+
+/// Instructions that can be gone back over, by default
+pub const HISTORY_LIMIT: usize = 100_000;
+
+/// What it takes to undo one instruction
+#[derive(Debug, Clone)]
+struct Undo {
+    // registers from before the instruction
+    registers: Registers,
+    // address of the instruction
+    pc: u16,
+    // writes it made, in order
+    writes: Vec<MemWrite>,
+}
+
+/// Breakpoints, watchpoints, and the state of the program being debugged
+#[derive(Debug)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // breakpoints and watchpoints are numbered together
+    next_id: usize,
+    // accesses caught by logging watchpoints, not taken yet
+    logged: Vec<WatchHit>,
+    // the program halted or failed, so it can't go on
+    finished: bool,
+    history: VecDeque<Undo>,
+    history_limit: usize,
+    // instructions run so far, minus those undone
+    count: u64,
+    // command repeated by an empty line
+    last_command: String,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+            logged: Vec::new(),
+            finished: false,
+            history: VecDeque::new(),
+            history_limit: HISTORY_LIMIT,
+            count: 0,
+            last_command: String::new(),
+        }
+    }
+
+    fn take_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Add a breakpoint, returning its number
+    pub fn add_breakpoint(&mut self, addr: u16) -> usize {
+        let id = self.take_id();
+        self.breakpoints.push(Breakpoint {
+            id,
+            addr,
+            enabled: true,
+            condition: None,
+            ignore: 0,
+            temporary: false,
+            hits: 0,
+        });
+        id
+    }
+
+    /// Look up a breakpoint by number, to change its condition, ignore count, ...
+    pub fn breakpoint_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.iter_mut().find(|b| b.id == id)
+    }
+
+    /// Watch the addresses from `start` to `end` (included), returning the watchpoint's number
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind, log: bool) -> usize {
+        let id = self.take_id();
+        self.watchpoints.push(Watchpoint {
+            id,
+            start,
+            end,
+            kind,
+            log,
+            enabled: true,
+        });
+        id
+    }
+
+    /// Delete a breakpoint or watchpoint by number, returning whether it existed
+    pub fn delete(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        self.breakpoints.len() + self.watchpoints.len() != len
+    }
+
+    /// Delete all breakpoints and watchpoints
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    /// Enable or disable a breakpoint or watchpoint by number, returning whether it exists
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        let breakpoints = self.breakpoints.iter_mut().filter(|b| b.id == id);
+        let watchpoints = self.watchpoints.iter_mut().filter(|w| w.id == id);
+        let flags = breakpoints
+            .map(|b| &mut b.enabled)
+            .chain(watchpoints.map(|w| &mut w.enabled));
+
+        let mut found = false;
+        for flag in flags {
+            *flag = enabled;
+            found = true;
+        }
+        found
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Check the breakpoints at PC, returning the first one that stops the program
+    ///
+    /// Breakpoints whose condition holds count a hit, and use up their ignore count first.
+    fn check_breakpoints(&mut self, vm: &VM) -> Option<usize> {
+        let mut stop = None;
+        for b in self.breakpoints.iter_mut() {
+            if !b.reached(vm) {
+                continue;
+            }
+            b.hits += 1;
+            if b.ignore > 0 {
+                b.ignore -= 1;
+            } else if stop.is_none() {
+                stop = Some(b.id);
+            }
+        }
+
+        if let Some(id) = stop {
+            self.breakpoints.retain(|b| !(b.id == id && b.temporary));
+        }
+        stop
+    }
+
+    /// Accesses caught by logging watchpoints since the last call, in order
+    pub fn take_logged(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.logged)
+    }
+
+    /// Check the accesses made by a step against the watchpoints
+    ///
+    /// Accesses caught by logging watchpoints are kept for `take_logged`.
+    /// Returns the first access caught by a watchpoint that stops.
+    fn check_watchpoints(&mut self, vm: &VM, outcome: &StepOutcome) -> Option<WatchHit> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+
+        let reads = outcome.mem_reads.iter().map(|r| {
+            let value = r.value;
+            (r.addr, false, Access::Read { value })
+        });
+        let writes = outcome.mem_writes.iter().map(|w| {
+            let (old, new) = (w.old, w.new);
+            (w.addr, true, Access::Write { old, new })
+        });
+
+        let mut stop = None;
+        for (addr, write, access) in reads.chain(writes) {
+            for w in self.watchpoints.iter().filter(|w| w.matches(addr, write)) {
+                let hit = WatchHit {
+                    id: w.id,
+                    pc: vm.last_pc(),
+                    addr,
+                    access,
+                };
+                if w.log {
+                    self.logged.push(hit);
+                } else if stop.is_none() {
+                    stop = Some(hit);
+                }
+            }
+        }
+        stop
+    }
+
+    /// Whether the program halted or failed, and can't be run any further
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Number of instructions run so far, minus those gone back over
+    pub fn instruction_count(&self) -> u64 {
+        self.count
+    }
+
+    /// Instruction count of the oldest instruction that can be gone back to
+    pub fn history_start(&self) -> u64 {
+        self.count - self.history.len() as u64
+    }
+
+    pub fn history_limit(&self) -> usize {
+        self.history_limit
+    }
+
+    /// Change how many instructions can be gone back over (0 stops recording)
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    fn record(&mut self, undo: Undo) {
+        self.count += 1;
+        if self.history_limit == 0 {
+            return;
+        }
+        if self.history.len() >= self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(undo);
+    }
+
+    /// Undo the latest instruction, if there is any history left
+    fn undo(&mut self, vm: &mut VM) -> Option<Undo> {
+        let undo = self.history.pop_back()?;
+        for w in undo.writes.iter().rev() {
+            vm.poke_mem(w.addr, w.old);
+        }
+        *vm.registers_mut() = undo.registers.clone();
+        self.count -= 1;
+        // whatever stopped the program has not happened yet
+        self.finished = false;
+        Some(undo)
+    }
+
+    /// Go back `n` instructions
+    pub fn reverse_step(&mut self, vm: &mut VM, n: usize) -> Stop {
+        self.reverse(vm, Some(n))
+    }
+
+    /// Go back until a breakpoint, or a write caught by a watchpoint
+    pub fn reverse_cont(&mut self, vm: &mut VM) -> Stop {
+        self.reverse(vm, None)
+    }
+
+    /// Go back or forward to the point where `count` instructions had run
+    ///
+    /// Going forward stops at breakpoints and watchpoints like `step` does.
+    pub fn goto(&mut self, vm: &mut VM, count: u64) -> Stop {
+        if count >= self.count {
+            return match count - self.count {
+                0 => Stop::Done,
+                n => self.step(vm, n as usize),
+            };
+        }
+
+        while self.count > count {
+            if self.undo(vm).is_none() {
+                return Stop::HistoryStart;
+            }
+        }
+        Stop::Done
+    }
+
+    // go back `limit` instructions, or without a limit until something stops us
+    fn reverse(&mut self, vm: &mut VM, limit: Option<usize>) -> Stop {
+        terminal_io::take_interrupt();
+
+        let mut undone = 0;
+        while limit != Some(undone) {
+            let Some(undo) = self.undo(vm) else {
+                return Stop::HistoryStart;
+            };
+            undone += 1;
+
+            // going back over a write is like seeing it happen
+            for w in &undo.writes {
+                let watch = self
+                    .watchpoints
+                    .iter()
+                    .find(|watch| !watch.log && watch.matches(w.addr, true));
+                if let Some(watch) = watch {
+                    return Stop::Watchpoint(WatchHit {
+                        id: watch.id,
+                        pc: undo.pc,
+                        addr: w.addr,
+                        access: Access::Write {
+                            old: w.old,
+                            new: w.new,
+                        },
+                    });
+                }
+            }
+            // hit and ignore counts are left alone, since these hits were already counted
+            if let Some(b) = self.breakpoints.iter().find(|b| b.reached(vm)) {
+                return Stop::Breakpoint(b.id);
+            }
+            if terminal_io::take_interrupt() {
+                return Stop::Interrupted;
+            }
+        }
+        Stop::Done
+    }
+
+    /// Execute `n` instructions, going into subroutines and service routines
+    pub fn step(&mut self, vm: &mut VM, n: usize) -> Stop {
+        let mut count = 0;
+        self.run(vm, |_, _| {
+            count += 1;
+            count >= n
+        })
+    }
+
+    /// Execute `n` instructions, running subroutine calls and traps to completion
+    pub fn next(&mut self, vm: &mut VM, n: usize) -> Stop {
+        for _ in 0..n {
+            let pc = vm.registers().pc();
+            let word = vm.peek_mem(pc);
+            let is_call = matches!(crate::vm::get_opcode(word), OpCode::JSR | OpCode::TRAP);
+            let stop = if is_call {
+                // run until we are back right after the call, at the same depth
+                let mut depth = 0;
+                self.run(vm, |vm, outcome| {
+                    depth += call_depth_change(vm, outcome);
+                    depth <= 0 && vm.registers().pc() == pc.wrapping_add(1)
+                })
+            } else {
+                self.step(vm, 1)
+            };
+            if !matches!(stop, Stop::Done) {
+                return stop;
+            }
+        }
+        Stop::Done
+    }
+
+    /// Run until the current subroutine (or service routine) returns
+    pub fn finish(&mut self, vm: &mut VM) -> Stop {
+        let mut depth = 0;
+        self.run(vm, |vm, outcome| {
+            depth += call_depth_change(vm, outcome);
+            depth < 0
+        })
+    }
+
+    /// Run until a breakpoint, or until the program stops
+    pub fn cont(&mut self, vm: &mut VM) -> Stop {
+        self.run(vm, |_, _| false)
+    }
+
+    /// Run until `done` returns true after a step, a breakpoint or watchpoint is hit, or CTRL-C
+    fn run(&mut self, vm: &mut VM, mut done: impl FnMut(&VM, &StepOutcome) -> bool) -> Stop {
+        if self.finished {
+            return Stop::Halted;
+        }
+
+        // forget any CTRL-C pressed at the prompt
+        terminal_io::take_interrupt();
+
+        let mut stop = Stop::Done;
+        let mut before = vm.registers().clone();
+        let result = vm.run_until(|vm, outcome| {
+            self.record(Undo {
+                registers: std::mem::replace(&mut before, vm.registers().clone()),
+                pc: vm.last_pc(),
+                writes: outcome.mem_writes.clone(),
+            });
+
+            if let Some(hit) = self.check_watchpoints(vm, outcome) {
+                stop = Stop::Watchpoint(hit);
+                return true;
+            }
+            if let Some(id) = self.check_breakpoints(vm) {
+                stop = Stop::Breakpoint(id);
+                return true;
+            }
+            if terminal_io::take_interrupt() {
+                stop = Stop::Interrupted;
+                return true;
+            }
+            done(vm, outcome)
+        });
+
+        match result {
+            Ok(StopReason::Condition) => stop,
+            Ok(StopReason::Halted) => {
+                self.finished = true;
+                Stop::Halted
+            }
+            Err(e) => {
+                // the failed instruction can be gone back over too
+                self.record(Undo {
+                    registers: before,
+                    pc: vm.last_pc(),
+                    writes: Vec::new(),
+                });
+                self.finished = true;
+                Stop::Error(e)
+            }
+        }
+    }
+}
+
+/// How a step changed the depth of nested subroutine and service routine calls
+fn call_depth_change(vm: &VM, outcome: &StepOutcome) -> i32 {
+    let mut change = 0;
+    // interrupts and exceptions enter a service routine
+    if outcome.interrupt.is_some() {
+        change += 1;
+    }
+    // the instruction did not complete, so only the exception handler counts
+    if outcome.exception.is_some() {
+        return change + 1;
+    }
+
+    let Some(word) = outcome.instruction else {
+        return change;
+    };
+    change
+        + match outcome.opcode {
+            Some(OpCode::JSR) => 1,
+            // natively emulated traps return straight away
+            Some(OpCode::TRAP) if outcome.pc_after != vm.last_pc().wrapping_add(1) => 1,
+            // RET
+            Some(OpCode::JMP) if (word >> 6) & 0x7 == 7 => -1,
+            Some(OpCode::RTI) => -1,
+            _ => 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::test_console::TestConsole;
+    use crate::vm::MCR;
+
+    // a VM with `source` loaded, PC at its origin
+    fn vm_with<'a>(console: &'a mut TestConsole, source: &str) -> VM<'a> {
+        let mut vm = VM::new(console);
+        let assembly = asm::assemble("t.asm", source).unwrap();
+        vm.load_assembly("t.asm", source, &assembly).unwrap();
+        vm
+    }
+
+    // reads DATA, writes DATA, then reads SELF and writes over it
+    const WATCHED: &str = "
+        .ORIG x3000
+        LD R0, DATA
+        ADD R0, R0, #1
+        ST R0, DATA
+        STI R0, SELF
+        HALT
+    DATA .FILL #5
+    SELF .FILL SELF
+        .END
+    ";
+    const DATA: u16 = 0x3005;
+    const SELF: u16 = 0x3006;
+
+    fn watch_hit(stop: Stop) -> WatchHit {
+        match stop {
+            Stop::Watchpoint(hit) => hit,
+            stop => panic!("expected a watchpoint, got {:?}", stop),
+        }
+    }
+
+    #[test]
+    fn read_watchpoint() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, WATCHED);
+        let mut debugger = Debugger::new();
+        let id = debugger.add_watchpoint(DATA, DATA, WatchKind::Read, false);
+
+        let hit = watch_hit(debugger.cont(&mut vm));
+        assert_eq!(
+            hit,
+            WatchHit {
+                id,
+                pc: 0x3000,
+                addr: DATA,
+                access: Access::Read { value: 5 }
+            }
+        );
+        assert_eq!(vm.registers().pc(), 0x3001);
+
+        // the write to DATA goes by
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+    }
+
+    #[test]
+    fn write_watchpoint() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, WATCHED);
+        let mut debugger = Debugger::new();
+        let id = debugger.add_watchpoint(DATA, SELF, WatchKind::Write, false);
+
+        let hit = watch_hit(debugger.cont(&mut vm));
+        assert_eq!(
+            hit,
+            WatchHit {
+                id,
+                pc: 0x3002,
+                addr: DATA,
+                access: Access::Write { old: 5, new: 6 }
+            }
+        );
+        assert_eq!(vm.registers().pc(), 0x3003);
+
+        let hit = watch_hit(debugger.cont(&mut vm));
+        assert_eq!((hit.pc, hit.addr), (0x3003, SELF));
+        assert_eq!(hit.access, Access::Write { old: SELF, new: 6 });
+    }
+
+    #[test]
+    fn access_watchpoint() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, WATCHED);
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(DATA, DATA, WatchKind::Access, false);
+
+        let hit = watch_hit(debugger.cont(&mut vm));
+        assert_eq!((hit.pc, hit.access), (0x3000, Access::Read { value: 5 }));
+        let hit = watch_hit(debugger.cont(&mut vm));
+        assert_eq!(
+            (hit.pc, hit.access),
+            (0x3002, Access::Write { old: 5, new: 6 })
+        );
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+    }
+
+    #[test]
+    fn read_shows_the_value_before_a_write_by_the_same_instruction() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, WATCHED);
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(SELF, SELF, WatchKind::Read, false);
+
+        let hit = watch_hit(debugger.cont(&mut vm));
+        assert_eq!(hit.pc, 0x3003);
+        assert_eq!(hit.access, Access::Read { value: SELF });
+        assert_eq!(vm.peek_mem(SELF), 6);
+    }
+
+    #[test]
+    fn logging_watchpoints_keep_going() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, WATCHED);
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(DATA, DATA, WatchKind::Access, true);
+
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+        let logged = debugger.take_logged();
+        let accesses: Vec<_> = logged.iter().map(|hit| (hit.pc, hit.access)).collect();
+        assert_eq!(
+            accesses,
+            [
+                (0x3000, Access::Read { value: 5 }),
+                (0x3002, Access::Write { old: 5, new: 6 }),
+            ]
+        );
+        assert!(debugger.take_logged().is_empty());
+    }
+
+    // changes a register, memory and the condition codes on each step before HALT
+    const UNDONE: &str = "
+        .ORIG x3000
+        ADD R1, R1, #-1
+        ST R1, DATA
+        NOT R2, R1
+        ADD R2, R2, #3
+        HALT
+    DATA .FILL #7
+        .END
+    ";
+    const CLOCK: u16 = 1 << 15;
+
+    // registers (with PSR and the saved stack pointers), DATA and MCR
+    fn state(vm: &VM) -> ([u16; 12], u16, u16) {
+        let registers = std::array::from_fn(|i| vm.registers().get_reg(i as u16).unwrap());
+        (registers, vm.peek_mem(0x3005), vm.peek_mem(MCR))
+    }
+
+    // state before each instruction up to HALT, and after HALT
+    fn states_up_to_halt(vm: &mut VM) -> Vec<([u16; 12], u16, u16)> {
+        let mut debugger = Debugger::new();
+        let mut states = vec![state(vm)];
+        for _ in 0..4 {
+            assert!(matches!(debugger.step(vm, 1), Stop::Done));
+            states.push(state(vm));
+        }
+        assert!(matches!(debugger.step(vm, 1), Stop::Halted));
+        states.push(state(vm));
+        states
+    }
+
+    #[test]
+    fn reverse_step_restores_registers_psr_and_memory() {
+        let mut console = TestConsole::default();
+        let expected = states_up_to_halt(&mut vm_with(&mut console, UNDONE));
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, UNDONE);
+        let mut debugger = Debugger::new();
+
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+        assert!(debugger.finished());
+        assert_eq!(state(&vm), expected[5]);
+        assert_eq!(vm.peek_mem(MCR) & CLOCK, 0);
+
+        for i in (0..5).rev() {
+            assert!(matches!(debugger.reverse_step(&mut vm, 1), Stop::Done));
+            assert_eq!(state(&vm), expected[i]);
+            assert_eq!(debugger.instruction_count(), i as u64);
+            assert!(!debugger.finished());
+        }
+        assert!(matches!(
+            debugger.reverse_step(&mut vm, 1),
+            Stop::HistoryStart
+        ));
+        assert_eq!(state(&vm), expected[0]);
+
+        // and the program runs again from there
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+        assert_eq!(state(&vm), expected[5]);
+    }
+
+    #[test]
+    fn reverse_cont_stops_at_watched_writes_and_breakpoints() {
+        let mut console = TestConsole::default();
+        let expected = states_up_to_halt(&mut vm_with(&mut console, UNDONE));
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, UNDONE);
+        let mut debugger = Debugger::new();
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+
+        let breakpoint = debugger.add_breakpoint(0x3003);
+        assert!(matches!(
+            debugger.reverse_cont(&mut vm),
+            Stop::Breakpoint(id) if id == breakpoint
+        ));
+        assert_eq!(state(&vm), expected[3]);
+        debugger.delete(breakpoint);
+
+        let watch = debugger.add_watchpoint(0x3005, 0x3005, WatchKind::Write, false);
+        let hit = watch_hit(debugger.reverse_cont(&mut vm));
+        assert_eq!(
+            hit,
+            WatchHit {
+                id: watch,
+                pc: 0x3001,
+                addr: 0x3005,
+                access: Access::Write {
+                    old: 7,
+                    new: 0xFFFF
+                }
+            }
+        );
+        assert_eq!(state(&vm), expected[1]);
+
+        assert!(matches!(debugger.reverse_cont(&mut vm), Stop::HistoryStart));
+        assert_eq!(state(&vm), expected[0]);
+    }
+
+    #[test]
+    fn goto_goes_both_ways_across_halt() {
+        let mut console = TestConsole::default();
+        let expected = states_up_to_halt(&mut vm_with(&mut console, UNDONE));
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, UNDONE);
+        let mut debugger = Debugger::new();
+
+        assert!(matches!(debugger.goto(&mut vm, 5), Stop::Halted));
+        assert_eq!(state(&vm), expected[5]);
+        assert!(matches!(debugger.goto(&mut vm, 2), Stop::Done));
+        assert_eq!(state(&vm), expected[2]);
+        assert_eq!(vm.peek_mem(MCR) & CLOCK, CLOCK);
+        assert!(matches!(debugger.goto(&mut vm, 4), Stop::Done));
+        assert_eq!(state(&vm), expected[4]);
+        assert!(matches!(debugger.goto(&mut vm, 0), Stop::Done));
+        assert_eq!(state(&vm), expected[0]);
+        assert!(matches!(debugger.goto(&mut vm, 5), Stop::Halted));
+        assert_eq!(state(&vm), expected[5]);
+    }
+
+    #[test]
+    fn history_limit_is_respected() {
+        let mut console = TestConsole::default();
+        let expected = states_up_to_halt(&mut vm_with(&mut console, UNDONE));
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, UNDONE);
+        let mut debugger = Debugger::new();
+        debugger.set_history_limit(2);
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+        assert_eq!(debugger.history_start(), 3);
+
+        assert!(matches!(
+            debugger.reverse_step(&mut vm, 5),
+            Stop::HistoryStart
+        ));
+        assert_eq!(debugger.instruction_count(), 3);
+        assert_eq!(state(&vm), expected[3]);
+        assert!(matches!(debugger.goto(&mut vm, 1), Stop::HistoryStart));
+        assert_eq!(state(&vm), expected[3]);
+
+        // lowering the limit forgets the oldest instructions
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+        debugger.set_history_limit(1);
+        assert_eq!(debugger.history_start(), 4);
+        assert!(matches!(
+            debugger.reverse_step(&mut vm, 2),
+            Stop::HistoryStart
+        ));
+        assert_eq!(state(&vm), expected[4]);
+
+        // and no limit at all records nothing
+        debugger.set_history_limit(0);
+        assert!(matches!(debugger.cont(&mut vm), Stop::Halted));
+        assert!(matches!(
+            debugger.reverse_step(&mut vm, 1),
+            Stop::HistoryStart
+        ));
+        assert_eq!(state(&vm), expected[5]);
+    }
+}
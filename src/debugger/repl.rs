@@ -0,0 +1,677 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// debugger commands
+//////////////////////////////
+
+use super::expr::{register_index, REGISTER_NAMES};
+use super::{Breakpoint, Debugger, Expr, Stop, WatchKind};
+use crate::asm::disassemble;
+use crate::vm::VM;
+use std::io::{self, Write};
+
+const HELP: &str = "\
+break LOC [if COND] (b) stop before executing the instruction at LOC, if COND holds
+tbreak LOC [if COND]    like break, but delete the breakpoint once it stops the program
+condition N [COND]      stop at breakpoint N only if COND holds (or always, without COND)
+ignore N COUNT          go past breakpoint N the next COUNT times it is hit
+watch LOC [N] [log]     stop after an instruction writes one of the N words at LOC (default 1),
+                        or with `log`, keep going and list the writes when the program stops
+rwatch, awatch          like watch, but for reads, or for both reads and writes
+delete [N]        (d)   delete breakpoint or watchpoint N, or all of them
+enable N, disable N     turn breakpoint or watchpoint N on or off
+info breakpoints  (i b) list breakpoints and watchpoints
+step [N]          (s)   execute N instructions (default 1)
+next [N]          (n)   like step, but run subroutine calls and traps to completion
+finish                  run until the current subroutine returns
+continue          (c)   run until a breakpoint, HALT or CTRL-C
+reverse-step [N]  (rs)  go back N instructions (default 1)
+reverse-continue  (rc)  go back until a breakpoint, or a write to a watched word
+goto COUNT              go back or forward to when COUNT instructions had run
+history [LIMIT]         show the instruction count, or keep the last LIMIT instructions
+registers         (i r) show registers
+x LOC [N]               show N words of memory (default 8)
+list [LOC [N]]    (l)   disassemble N instructions at LOC (default: around PC)
+set TARGET VALUE        write a register (R0-R7, PC, PSR, USP, SSP) or memory
+where                   show the instruction about to run
+quit              (q)   leave the debugger
+
+LOC, VALUE and COND are expressions over numbers (x3000, #12, 12, 'A'), labels, registers,
+condition flags (N, Z, P) and memory (mem[R6]), e.g. `LOOP+2` or `R0 == x41 && mem[x4000] > 3`.
+Comparisons are signed. Spaces are not allowed in LOC and VALUE.
+Going back restores registers and memory, but keys that were read and output stay as they are.
+An empty line repeats the last command.";
+
+// instructions shown by `list` without arguments, and how many of them come before PC
+const LIST_LEN: u16 = 10;
+const LIST_BEFORE: u16 = 3;
+
+impl Debugger {
+    /// Read commands with `read_line` and run them, until `quit` or the end of the input
+    pub fn repl(
+        &mut self,
+        vm: &mut VM,
+        mut read_line: impl FnMut() -> Option<String>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        self.show_location(vm, out)?;
+        loop {
+            write!(out, "(lc3) ")?;
+            out.flush()?;
+            let Some(line) = read_line() else {
+                writeln!(out)?;
+                return Ok(());
+            };
+            if !self.command(vm, &line, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run a single command, returning false if it was `quit`
+    pub fn command(&mut self, vm: &mut VM, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let line = if line.trim().is_empty() {
+            self.last_command.clone()
+        } else {
+            self.last_command = line.to_string();
+            line.to_string()
+        };
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        if matches!(command, "q" | "quit") {
+            return Ok(false);
+        }
+
+        match self.dispatch(vm, command, args, out) {
+            Ok(()) => Ok(true),
+            Err(CommandError::Io(e)) => Err(e),
+            Err(CommandError::Message(msg)) => {
+                writeln!(out, "error: {}", msg)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        vm: &mut VM,
+        command: &str,
+        args: &[&str],
+        out: &mut impl Write,
+    ) -> Result<(), CommandError> {
+        match command {
+            "h" | "help" => Ok(writeln!(out, "{}", HELP)?),
+            "b" | "break" => self.cmd_break(vm, args, false, out),
+            "tbreak" => self.cmd_break(vm, args, true, out),
+            "condition" => self.cmd_condition(vm, args),
+            "ignore" => self.cmd_ignore(args),
+            "watch" => self.cmd_watch(vm, args, WatchKind::Write, out),
+            "rwatch" => self.cmd_watch(vm, args, WatchKind::Read, out),
+            "awatch" => self.cmd_watch(vm, args, WatchKind::Access, out),
+            "d" | "delete" => self.cmd_delete(args, out),
+            "enable" => self.cmd_enable(args, true),
+            "disable" => self.cmd_enable(args, false),
+            "i" | "info" => match args.first().copied() {
+                Some("b" | "break" | "breakpoints") => self.cmd_breakpoints(vm, out),
+                Some("r" | "registers") => cmd_registers(vm, out),
+                _ => Err("try `info breakpoints` or `info registers`".into()),
+            },
+            "registers" | "regs" => cmd_registers(vm, out),
+            "s" | "step" => {
+                let n = count_arg(args)?;
+                self.resume(vm, |d, vm| d.step(vm, n), out)
+            }
+            "n" | "next" => {
+                let n = count_arg(args)?;
+                self.resume(vm, |d, vm| d.next(vm, n), out)
+            }
+            "finish" => self.resume(vm, Debugger::finish, out),
+            "c" | "continue" => self.resume(vm, Debugger::cont, out),
+            "rs" | "reverse-step" => {
+                let n = count_arg(args)?;
+                let stop = self.reverse_step(vm, n);
+                self.report(vm, stop, out)
+            }
+            "rc" | "reverse-continue" => {
+                let stop = self.reverse_cont(vm);
+                self.report(vm, stop, out)
+            }
+            "goto" => self.cmd_goto(vm, args, out),
+            "history" => self.cmd_history(args, out),
+            "x" => cmd_examine(vm, args, out),
+            "l" | "list" => self.cmd_list(vm, args, out),
+            "set" => cmd_set(vm, args),
+            "where" => Ok(self.show_location(vm, out)?),
+            _ => Err(format!("unknown command `{}` (try `help`)", command).into()),
+        }
+    }
+
+    /// Show the instruction about to run, with its source line if known
+    pub fn show_location(&self, vm: &VM, out: &mut impl Write) -> io::Result<()> {
+        let pc = vm.registers().pc();
+        let word = vm.peek_mem(pc);
+        writeln!(
+            out,
+            "=> x{:04X}{}  {:04X}  {}",
+            pc,
+            describe_addr(vm, pc),
+            word,
+            disassemble(word, pc, vm.symbols())
+        )?;
+        if let Some(location) = vm.source_location(pc) {
+            writeln!(out, "   {}", location)?;
+        }
+        Ok(())
+    }
+
+    // run the program with `f`, then say why it stopped
+    fn resume(
+        &mut self,
+        vm: &mut VM,
+        f: impl FnOnce(&mut Debugger, &mut VM) -> Stop,
+        out: &mut impl Write,
+    ) -> Result<(), CommandError> {
+        if self.finished {
+            return Err("the program is not running anymore (but reverse-step can go back)".into());
+        }
+
+        let stop = f(self, vm);
+        for hit in self.take_logged() {
+            writeln!(out, "Watchpoint {}: {}", hit.id, hit.describe(vm))?;
+        }
+        self.report(vm, stop, out)
+    }
+
+    // say why the program stopped, and where
+    fn report(&self, vm: &VM, stop: Stop, out: &mut impl Write) -> Result<(), CommandError> {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint(id) => {
+                let pc = vm.registers().pc();
+                writeln!(
+                    out,
+                    "\nBreakpoint {}, x{:04X}{}",
+                    id,
+                    pc,
+                    describe_addr(vm, pc)
+                )?;
+            }
+            Stop::Watchpoint(hit) => {
+                writeln!(out, "\nWatchpoint {}: {}", hit.id, hit.describe(vm))?
+            }
+            Stop::Interrupted => writeln!(out, "\nInterrupted")?,
+            Stop::Halted => {
+                writeln!(out, "\nProgram halted")?;
+                return Ok(());
+            }
+            Stop::HistoryStart => writeln!(out, "\nReached the start of the history")?,
+            Stop::Error(e) => {
+                write!(out, "\nerror: {}", e)?;
+                if let Some(label) = vm.symbolize(vm.last_pc()) {
+                    write!(out, " (in {})", label)?;
+                }
+                writeln!(out)?;
+                if let Some(location) = vm.source_location(vm.last_pc()) {
+                    writeln!(out, "  --> {}", location)?;
+                }
+                return Ok(());
+            }
+        }
+        self.show_location(vm, out)?;
+        Ok(())
+    }
+
+    fn cmd_goto(
+        &mut self,
+        vm: &mut VM,
+        args: &[&str],
+        out: &mut impl Write,
+    ) -> Result<(), CommandError> {
+        let [count] = args else {
+            return Err("usage: goto COUNT".into());
+        };
+        let count: u64 = count
+            .parse()
+            .map_err(|_| format!("`{}` is not an instruction count", count))?;
+
+        if count > self.count {
+            self.resume(vm, |d, vm| d.goto(vm, count), out)
+        } else {
+            let stop = self.goto(vm, count);
+            self.report(vm, stop, out)
+        }
+    }
+
+    fn cmd_history(&mut self, args: &[&str], out: &mut impl Write) -> Result<(), CommandError> {
+        match args {
+            [] => {}
+            [limit] => {
+                let limit = limit
+                    .parse()
+                    .map_err(|_| format!("`{}` is not a count", limit))?;
+                self.set_history_limit(limit);
+            }
+            _ => return Err("usage: history [LIMIT]".into()),
+        }
+
+        writeln!(
+            out,
+            "{} instructions run; can go back to {} (keeping up to {})",
+            self.count,
+            self.history_start(),
+            self.history_limit
+        )?;
+        Ok(())
+    }
+
+    fn cmd_break(
+        &mut self,
+        vm: &VM,
+        args: &[&str],
+        temporary: bool,
+        out: &mut impl Write,
+    ) -> Result<(), CommandError> {
+        let (loc, condition) = match args {
+            [loc] => (loc, None),
+            [loc, "if", cond @ ..] if !cond.is_empty() => {
+                (loc, Some(Expr::parse(&cond.join(" "), vm.symbols())?))
+            }
+            _ => return Err("usage: break LOC [if COND]".into()),
+        };
+        let addr = parse_value(vm, loc)?;
+
+        let id = self.add_breakpoint(addr);
+        let b = self.breakpoint_mut(id).unwrap();
+        b.condition = condition;
+        b.temporary = temporary;
+        let kind = if temporary {
+            "Temporary breakpoint"
+        } else {
+            "Breakpoint"
+        };
+        writeln!(
+            out,
+            "{} {} at x{:04X}{}",
+            kind,
+            id,
+            addr,
+            describe_addr(vm, addr)
+        )?;
+        Ok(())
+    }
+
+    fn cmd_condition(&mut self, vm: &VM, args: &[&str]) -> Result<(), CommandError> {
+        let [id, cond @ ..] = args else {
+            return Err("usage: condition N [COND]".into());
+        };
+        let condition = match cond {
+            [] => None,
+            _ => Some(Expr::parse(&cond.join(" "), vm.symbols())?),
+        };
+        self.existing_breakpoint(id)?.condition = condition;
+        Ok(())
+    }
+
+    fn cmd_ignore(&mut self, args: &[&str]) -> Result<(), CommandError> {
+        let [id, count] = args else {
+            return Err("usage: ignore N COUNT".into());
+        };
+        let count = count
+            .parse()
+            .map_err(|_| format!("`{}` is not a count", count))?;
+        self.existing_breakpoint(id)?.ignore = count;
+        Ok(())
+    }
+
+    fn existing_breakpoint(&mut self, id: &str) -> Result<&mut Breakpoint, CommandError> {
+        let id = breakpoint_arg(id)?;
+        self.breakpoint_mut(id)
+            .ok_or_else(|| format!("no breakpoint {}", id).into())
+    }
+
+    fn cmd_watch(
+        &mut self,
+        vm: &VM,
+        args: &[&str],
+        kind: WatchKind,
+        out: &mut impl Write,
+    ) -> Result<(), CommandError> {
+        let (args, log) = match args {
+            [rest @ .., "log"] => (rest, true),
+            _ => (args, false),
+        };
+        let (start, len) = match args {
+            [loc] => (parse_value(vm, loc)?, 1),
+            [loc, n] => (parse_value(vm, loc)?, word_count_arg(n)?),
+            _ => return Err("usage: watch/rwatch/awatch LOC [N] [log]".into()),
+        };
+        let end = u16::try_from(start as usize + len as usize - 1)
+            .map_err(|_| "the watched range goes past the end of memory")?;
+
+        let id = self.add_watchpoint(start, end, kind, log);
+        writeln!(
+            out,
+            "Watchpoint {} ({}) on {}",
+            id,
+            kind,
+            describe_range(vm, start, end)
+        )?;
+        Ok(())
+    }
+
+    fn cmd_delete(&mut self, args: &[&str], out: &mut impl Write) -> Result<(), CommandError> {
+        match args {
+            [] => {
+                self.clear();
+                writeln!(out, "Deleted all breakpoints and watchpoints")?;
+                Ok(())
+            }
+            [id] => {
+                let id = breakpoint_arg(id)?;
+                if !self.delete(id) {
+                    return Err(format!("no breakpoint or watchpoint {}", id).into());
+                }
+                Ok(())
+            }
+            _ => Err("usage: delete [N]".into()),
+        }
+    }
+
+    fn cmd_enable(&mut self, args: &[&str], enabled: bool) -> Result<(), CommandError> {
+        let [id] = args else {
+            return Err("usage: enable N / disable N".into());
+        };
+        let id = breakpoint_arg(id)?;
+        if !self.set_enabled(id, enabled) {
+            return Err(format!("no breakpoint or watchpoint {}", id).into());
+        }
+        Ok(())
+    }
+
+    fn cmd_breakpoints(&self, vm: &VM, out: &mut impl Write) -> Result<(), CommandError> {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            writeln!(out, "No breakpoints or watchpoints")?;
+        }
+
+        // number, description, enabled, and details for the following lines
+        let mut rows: Vec<(usize, String, bool, Vec<String>)> = Vec::new();
+        for b in &self.breakpoints {
+            let kind = if b.temporary {
+                "tmp breakpoint"
+            } else {
+                "breakpoint"
+            };
+            let what = format!("{:18}x{:04X}{}", kind, b.addr, describe_addr(vm, b.addr));
+            let mut details = Vec::new();
+            if let Some(condition) = &b.condition {
+                details.push(format!("stop only if {}", condition));
+            }
+            if b.ignore > 0 {
+                details.push(format!("ignore next {} hits", b.ignore));
+            }
+            if b.hits > 0 {
+                let times = if b.hits == 1 { "time" } else { "times" };
+                details.push(format!("hit {} {}", b.hits, times));
+            }
+            rows.push((b.id, what, b.enabled, details));
+        }
+        for w in &self.watchpoints {
+            let what = format!(
+                "{:18}{}{}",
+                format!("{} watchpoint", w.kind),
+                describe_range(vm, w.start, w.end),
+                if w.log { "  (log)" } else { "" }
+            );
+            rows.push((w.id, what, w.enabled, Vec::new()));
+        }
+        rows.sort_by_key(|&(id, ..)| id);
+
+        for (id, what, enabled, details) in rows {
+            let disabled = if enabled { "" } else { "  (disabled)" };
+            writeln!(out, "{:<4}{}{}", id, what, disabled)?;
+            for line in details {
+                writeln!(out, "        {}", line)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cmd_list(&self, vm: &VM, args: &[&str], out: &mut impl Write) -> Result<(), CommandError> {
+        let pc = vm.registers().pc();
+        let (start, len) = match args {
+            [] => (pc.saturating_sub(LIST_BEFORE), LIST_LEN),
+            [loc] => (parse_value(vm, loc)?, LIST_LEN),
+            [loc, n] => (parse_value(vm, loc)?, word_count_arg(n)?),
+            _ => return Err("usage: list [LOC [N]]".into()),
+        };
+
+        for addr in (0..len).map(|i| start.wrapping_add(i)) {
+            let word = vm.peek_mem(addr);
+            let breakpoint = self.breakpoints.iter().any(|b| b.enabled && b.addr == addr);
+            writeln!(
+                out,
+                "{}{} x{:04X}  {:04X}  {:16}{}",
+                if breakpoint { '*' } else { ' ' },
+                if addr == pc { '>' } else { ' ' },
+                addr,
+                word,
+                vm.symbols().name_at(addr).unwrap_or(""),
+                disassemble(word, addr, vm.symbols())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn cmd_registers(vm: &VM, out: &mut impl Write) -> Result<(), CommandError> {
+    let registers = vm.registers();
+    for (idx, name) in REGISTER_NAMES.iter().enumerate() {
+        let val = registers.get_reg(idx as u16).map_err(|e| e.to_string())?;
+        let note = match *name {
+            "PC" => describe_addr(vm, val),
+            "PSR" => format!("  {}", registers.describe_psr()),
+            _ => format!("  #{}", val as i16),
+        };
+        writeln!(out, "{:<4} x{:04X}{}", name, val, note)?;
+    }
+    Ok(())
+}
+
+fn cmd_examine(vm: &VM, args: &[&str], out: &mut impl Write) -> Result<(), CommandError> {
+    let (start, len) = match args {
+        [loc] => (parse_value(vm, loc)?, 8),
+        [loc, n] => (parse_value(vm, loc)?, word_count_arg(n)?),
+        _ => return Err("usage: x LOC [N]".into()),
+    };
+
+    for addr in (0..len).map(|i| start.wrapping_add(i)) {
+        let word = vm.peek_mem(addr);
+        let c = match word {
+            0x20..=0x7E => format!("  '{}'", word as u8 as char),
+            _ => String::new(),
+        };
+        writeln!(
+            out,
+            "x{:04X}  {:16}x{:04X}  #{}{}",
+            addr,
+            vm.symbols().name_at(addr).unwrap_or(""),
+            word,
+            word as i16,
+            c
+        )?;
+    }
+    Ok(())
+}
+
+fn cmd_set(vm: &mut VM, args: &[&str]) -> Result<(), CommandError> {
+    let [target, value] = args else {
+        return Err("usage: set TARGET VALUE".into());
+    };
+    let value = parse_value(vm, value)?;
+    match register_index(target) {
+        Some(idx) => vm.registers_mut().set_reg(idx, value),
+        None => {
+            let addr = parse_value(vm, target)?;
+            vm.write_mem(addr, value)
+        }
+    }
+    .map_err(|e| e.to_string().into())
+}
+
+////////////////
+// arguments
+////////////////
+
+// NOTE
+// Commands fail with a message that is shown to the user, without leaving the debugger.
+// Only failing to write the output is a real error.
+
+enum CommandError {
+    Io(io::Error),
+    Message(String),
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> Self {
+        CommandError::Io(e)
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(msg: String) -> Self {
+        CommandError::Message(msg)
+    }
+}
+
+impl From<&str> for CommandError {
+    fn from(msg: &str) -> Self {
+        CommandError::Message(msg.to_string())
+    }
+}
+
+/// ` (LABEL+n)` for an address near a label, or nothing
+fn describe_addr(vm: &VM, addr: u16) -> String {
+    vm.symbolize(addr)
+        .map(|s| format!(" ({})", s))
+        .unwrap_or_default()
+}
+
+/// `xSTART-xEND (LABEL)` for a range of addresses, or just the address if there is only one
+fn describe_range(vm: &VM, start: u16, end: u16) -> String {
+    if start == end {
+        format!("x{:04X}{}", start, describe_addr(vm, start))
+    } else {
+        format!("x{:04X}-x{:04X}{}", start, end, describe_addr(vm, start))
+    }
+}
+
+/// Optional repeat count
+fn count_arg(args: &[&str]) -> Result<usize, CommandError> {
+    match args {
+        [] => Ok(1),
+        [n] => match n.parse() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("`{}` is not a positive count", n).into()),
+        },
+        _ => Err("too many arguments".into()),
+    }
+}
+
+// a number of words, which can't be more than memory holds
+fn word_count_arg(n: &str) -> Result<u16, CommandError> {
+    u16::try_from(count_arg(&[n])?)
+        .map_err(|_| format!("`{}` is more words than memory holds", n).into())
+}
+
+fn breakpoint_arg(id: &str) -> Result<usize, CommandError> {
+    id.parse()
+        .map_err(|_| format!("`{}` is not a breakpoint number", id).into())
+}
+
+/// Evaluate an expression given as a single argument
+fn parse_value(vm: &VM, text: &str) -> Result<u16, CommandError> {
+    Ok(Expr::parse(text, vm.symbols())?.eval(vm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::test_console::TestConsole;
+
+    // output of each command, run in order on `source` with a default debugger
+    fn outputs(source: &str, commands: &[&str]) -> Vec<String> {
+        let mut console = TestConsole::default();
+        let mut vm = VM::new(&mut console);
+        let assembly = asm::assemble("t.asm", source).unwrap();
+        vm.load_assembly("t.asm", source, &assembly).unwrap();
+
+        let mut debugger = Debugger::default();
+        commands
+            .iter()
+            .map(|command| {
+                let mut out = Vec::new();
+                debugger.command(&mut vm, command, &mut out).unwrap();
+                String::from_utf8(out).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn counts_must_fit() {
+        let out = outputs(
+            ".ORIG x3000\nHALT\n.END",
+            &[
+                "list x3000 65536",
+                "x x3000 70000",
+                "watch x3000 99999999999",
+                "watch xFFFF 2",
+                "x xFFFF 2",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "error: `65536` is more words than memory holds\n",
+                "error: `70000` is more words than memory holds\n",
+                "error: `99999999999` is more words than memory holds\n",
+                "error: the watched range goes past the end of memory\n",
+                "xFFFF                  x0000  #0\nx0000                  x0000  #0\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn default_debugger_keeps_history() {
+        let out = outputs(
+            ".ORIG x3000\nADD R0, R0, #1\nADD R0, R0, #1\nHALT\n.END",
+            &["s 2", "rs", "history"],
+        );
+        assert!(out[1].contains("x3001"), "{}", out[1]);
+        assert!(out[2].starts_with("1 instruction"), "{}", out[2]);
+    }
+
+    #[test]
+    fn logged_accesses_are_listed_when_the_program_stops() {
+        let source = ".ORIG x3000\nLD R0, DATA\nST R0, DATA\nHALT\nDATA .FILL #5\n.END";
+        let out = outputs(source, &["awatch DATA 1 log", "continue"]);
+        assert_eq!(
+            out[1],
+            "Watchpoint 1: x3000 read x3003 (DATA): x0005\n\
+             Watchpoint 1: x3001 wrote x3003 (DATA): x0005 -> x0005\n\
+             \nProgram halted\n"
+        );
+    }
+}
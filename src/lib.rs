@@ -0,0 +1,66 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//! An LC-3 virtual machine.
+//!
+//! The [`VM`] holds the memory and registers of an LC-3 machine, and talks to the outside world
+//! through a keyboard and a display (see [`terminal_io`]).
+//! [`terminal_io::TerminalIO`] connects them to the real terminal,
+//! but any type implementing [`KeyboardIO`] and [`DisplayIO`] can stand in for it.
+//! Programs can be assembled from source with the [`asm`] module,
+//! and debugged interactively with the [`debugger`].
+//!
+//! ```
+//! use lc3::{DisplayIO, KeyboardIO, StopReason, VM};
+//!
+//! /// Records output, and never has any keys to give
+//! struct Capture(Vec<u8>);
+//!
+//! impl KeyboardIO for Capture {
+//!     fn get_key(&mut self) -> Option<u8> {
+//!         None
+//!     }
+//!     fn check_key(&mut self) -> bool {
+//!         false
+//!     }
+//! }
+//!
+//! impl DisplayIO for Capture {
+//!     fn put_char(&mut self, c: u8) {
+//!         self.0.push(c);
+//!     }
+//! }
+//!
+//! let mut io = Capture(Vec::new());
+//! let mut vm = VM::new(&mut io);
+//! vm.read_program("programs/hello-world.obj")?;
+//! assert_eq!(vm.execute()?, StopReason::Halted);
+//! println!("R0 = {:#x}", vm.registers().get_reg(0)?);
+//!
+//! drop(vm);
+//! assert!(io.0.starts_with(b"Hello World!"));
+//! # Ok::<(), lc3::VmError>(())
+//! ```
+
+pub mod asm;
+pub mod debugger;
+pub mod vm;
+
+#[cfg(test)]
+mod test_console;
+
+pub use vm::terminal_io::{self, ConsoleIO, DisplayIO, KeyboardIO};
+pub use vm::{loader, number, symbols};
+pub use vm::{
+    Exception, ExceptionFallback, MemWrite, OpCode, RegWrite, Registers, Segment, SourceLocation,
+    StepOutcome, StopReason, SymbolTable, TrapMode, VmError, DDR, DSR, KBDR, KBSR, MCR, MEM_SIZE,
+    VM,
+};
@@ -13,29 +13,504 @@ You should have received a copy of the GNU General Public License along with thi
 ////// driver code
 //////////////////////////////
 
-mod vm;
-use crate::vm::{terminal_io, VM};
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use lc3::asm::link::Module;
+use lc3::debugger::{Debugger, GdbEnd};
+use lc3::{
+    asm, loader, number, terminal_io, ExceptionFallback, Segment, StopReason, SymbolTable, VM,
+};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // running programs is the default
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run programs (the default); `.asm` files are assembled first
+    Run(RunArgs),
+    /// Assemble LC-3 source into an object file and a symbol table
+    Asm(AsmArgs),
+    /// Print an annotated disassembly of a program
+    Disasm(DisasmArgs),
+    /// Link relocatable modules into an executable object
+    Link(LinkArgs),
+}
+
+#[derive(Args, Debug)]
+struct LinkArgs {
+    /// Relocatable objects (or assembly sources), placed one after the other in this order;
+    /// execution starts at the first
+    #[arg(required = true)]
+    modules: Vec<PathBuf>,
+
+    /// Object file to write; the symbol table is written next to it, with a .sym extension
+    #[arg(short, long, default_value = "a.obj")]
+    output: PathBuf,
+
+    /// Address to place the first module at (hex)
+    #[arg(long, value_parser = parse_addr, default_value = "x3000")]
+    base: u16,
+}
+
+#[derive(Args, Debug)]
+struct DisasmArgs {
+    /// Program file (`prog.sym` next to `prog.obj` is used for labels)
+    program: PathBuf,
+
+    /// Extra symbol table to take labels from
+    #[arg(long = "symbols", value_name = "FILE")]
+    symbols: Vec<PathBuf>,
+
+    /// Write a listing (.lst) next to the program instead of printing the disassembly
+    #[arg(long)]
+    listing: bool,
+}
+
+#[derive(Args, Debug)]
+struct AsmArgs {
+    /// Assembly source file
+    source: PathBuf,
+
+    /// Object file to write (defaults to the source file with an .obj extension);
+    /// the symbol table is written next to it, with a .sym extension
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Write an lc3tools object, which can hold several .ORIG blocks
+    #[arg(long, conflicts_with = "relocatable")]
+    lc3tools: bool,
+
+    /// Write a relocatable object (.rel) for `lc3 link`, which may use .IMPORT and .EXPORT
+    #[arg(long)]
+    relocatable: bool,
+
+    /// Also write a listing (.lst) next to the object file
+    #[arg(long)]
+    listing: bool,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
     /// Enables debug printing to stderr (which can be separately piped to a file).
     #[arg(long)]
     debug: bool,
 
-    /// Program file
-    program: Option<String>,
+    /// Start paused in an interactive debugger (type `help` at its prompt for commands).
+    #[arg(long)]
+    debugger: bool,
+
+    /// Serve the program over the gdb remote protocol, on a TCP port (`1234`, `host:1234`) or a Unix socket (a path).
+    ///
+    /// Stock gdb has no LC-3 architecture, so this is for clients that read the register layout from `target.xml`.
+    #[arg(long, value_name = "ADDR", conflicts_with = "debugger")]
+    gdb: Option<String>,
+
+    /// Jump through empty exception vectors like the hardware would, instead of halting with a diagnostic.
+    #[arg(long)]
+    raw_exceptions: bool,
+
+    /// Load the bundled LC-3 operating system and run trap routines through it, instead of natively.
+    #[arg(long)]
+    os: bool,
+
+    /// Start the program in user mode, on the user stack, where system space and device registers are protected.
+    #[arg(long)]
+    user_mode: bool,
+
+    /// Address to start executing at (hex, e.g. x3000); defaults to the origin of the last program.
+    #[arg(long, value_parser = parse_addr)]
+    entry: Option<u16>,
+
+    /// Extra symbol table to load (`prog.sym` next to `prog.obj` is picked up automatically)
+    #[arg(long = "symbols", value_name = "FILE")]
+    symbols: Vec<String>,
+
+    /// Program files, loaded in order at their own origins
+    /// (`-` reads one from stdin, and `.asm` sources are assembled)
+    #[arg(required = true)]
+    programs: Vec<String>,
+}
+
+/// Parse an address, read as hex unless it has another prefix (like `#12288`)
+fn parse_addr(s: &str) -> Result<u16, String> {
+    number::parse_word(s, 16).ok_or_else(|| format!("invalid address: {}", s))
 }
 
 fn main() {
-    let cli = Args::parse();
+    let cli = Cli::parse();
+
+    // the terminal is restored when `run` returns, so it is safe to exit afterwards
+    let result = match cli.command {
+        Some(Command::Asm(args)) => assemble(args),
+        Some(Command::Disasm(args)) => disassemble(args),
+        Some(Command::Link(args)) => link(args),
+        Some(Command::Run(args)) => run(args).map(|_| ()),
+        None => run(cli.run).map(|_| ()),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn assemble(args: AsmArgs) -> Result<(), Box<dyn Error>> {
+    let name = args.source.display().to_string();
+    let source = fs::read_to_string(&args.source).map_err(|e| format!("{}: {}", name, e))?;
+    let assembly = asm::assemble(&name, &source)
+        .map_err(|e| format!("could not assemble {}:\n{}", name, e))?;
+
+    let default_ext = if args.relocatable { "rel" } else { "obj" };
+    let obj_path = args
+        .output
+        .unwrap_or_else(|| args.source.with_extension(default_ext));
+    let obj = if args.relocatable {
+        let mut rel = Vec::new();
+        Module::from_assembly(&name, &assembly)?.write(&mut rel)?;
+        rel
+    } else {
+        require_linked(&name, &assembly)?;
+        plain_object(&name, &source, &assembly, args.lc3tools)?
+    };
+
+    // nothing is written until the object is known to be good
+    if args.listing {
+        write_file(
+            &obj_path.with_extension("lst"),
+            asm::listing(&assembly, &source),
+        )?;
+    }
+    write_file(&obj_path, obj)?;
+    if args.relocatable {
+        return Ok(());
+    }
+    write_file(&obj_path.with_extension("sym"), assembly.symbols.to_lc3as())
+}
+
+/// Encode a linked program as a plain or lc3tools object
+fn plain_object(
+    name: &str,
+    source: &str,
+    assembly: &asm::Assembly,
+    lc3tools: bool,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut obj = Vec::new();
+    if lc3tools {
+        let lines: Vec<&str> = source.lines().collect();
+        loader::write_lc3tools(
+            &assembly.segments,
+            |addr| {
+                assembly
+                    .source_map
+                    .get(&addr)
+                    .map(|&line| lines[line - 1].trim().to_string())
+                    .unwrap_or_default()
+            },
+            &mut obj,
+        )?;
+    } else {
+        match &assembly.segments[..] {
+            [segment] => loader::write_obj(segment, &mut obj)?,
+            [] => return Err(format!("{}: no .ORIG block to assemble", name).into()),
+            _ => {
+                return Err(format!(
+                    "{}: several .ORIG blocks need an lc3tools object (pass --lc3tools)",
+                    name
+                )
+                .into())
+            }
+        }
+    }
+    Ok(obj)
+}
+
+// write an output file, naming it in errors
+fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), Box<dyn Error>> {
+    fs::write(path, contents).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Refuse programs that still need symbols from other modules
+fn require_linked(name: &str, assembly: &asm::Assembly) -> Result<(), Box<dyn Error>> {
+    match assembly.fixups.first() {
+        Some(fixup) => Err(format!(
+            "{}: `{}` is imported from another module; assemble with --relocatable and use `lc3 link`",
+            name, fixup.symbol
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+fn link(args: LinkArgs) -> Result<(), Box<dyn Error>> {
+    let mut modules = Vec::new();
+    for path in &args.modules {
+        let name = path.display().to_string();
+        let module = if path.extension().is_some_and(|ext| ext == "asm") {
+            let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", name, e))?;
+            let assembly = asm::assemble(&name, &source)
+                .map_err(|e| format!("could not assemble {}:\n{}", name, e))?;
+            Module::from_assembly(&name, &assembly)?
+        } else {
+            let bytes = fs::read(path).map_err(|e| format!("{}: {}", name, e))?;
+            Module::read(&name, &bytes).map_err(|e| format!("{}: {}", name, e))?
+        };
+        modules.push(module);
+    }
+
+    let linked =
+        asm::link::link(&modules, args.base).map_err(|e| format!("could not link:\n{}", e))?;
+
+    let mut obj = Vec::new();
+    loader::write_obj(&linked.segment, &mut obj)?;
+    write_file(&args.output, obj)?;
+    write_file(
+        &args.output.with_extension("sym"),
+        linked.symbols.to_lc3as(),
+    )
+}
+
+fn disassemble(args: DisasmArgs) -> Result<(), Box<dyn Error>> {
+    let name = args.program.display().to_string();
+    let segments = loader::read_file(&args.program).map_err(|e| format!("{}: {}", name, e))?;
+
+    let mut symbols = SymbolTable::new();
+    let mut sym_paths = args.symbols;
+    let sym_path = args.program.with_extension("sym");
+    if sym_path != args.program && sym_path.is_file() {
+        sym_paths.insert(0, sym_path);
+    }
+    for path in sym_paths {
+        let table = SymbolTable::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        symbols.extend(&table);
+    }
+
+    if args.listing {
+        return write_file(
+            &args.program.with_extension("lst"),
+            asm::listing_from_disassembly(&segments, &symbols),
+        );
+    }
+
+    match print_disassembly(&name, &segments, &symbols) {
+        // the reader went away (e.g. `lc3 disasm prog.obj | head`)
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        result => result?,
+    }
+
+    Ok(())
+}
+
+// print each segment as an annotated listing
+fn print_disassembly(name: &str, segments: &[Segment], symbols: &SymbolTable) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    for segment in segments {
+        writeln!(out, "; {} at {}", name, loader::fmt_range(&segment.range()))?;
+        writeln!(out, "{:13}{:16}.ORIG x{:04X}", "", "", segment.origin)?;
+        for line in asm::disassemble_segment(segment, symbols) {
+            let text = match &line.comment {
+                Some(comment) => format!("{:24}; {}", line.text, comment),
+                None => line.text,
+            };
+            writeln!(
+                out,
+                "x{:04X}  {:04X}  {:16}{}",
+                line.addr,
+                line.word,
+                line.label.unwrap_or_default(),
+                text
+            )?;
+        }
+        writeln!(out, "{:13}{:16}.END", "", "")?;
+    }
+
+    Ok(())
+}
+
+fn run(cli: RunArgs) -> Result<StopReason, Box<dyn Error>> {
+    // stdin has to be read before the terminal takes it over for the keyboard
+    let stdin_program = if cli.programs.iter().any(|p| p == "-") {
+        let mut bytes = Vec::new();
+        io::stdin()
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("-: {}", e))?;
+        bytes
+    } else {
+        Vec::new()
+    };
 
     let mut term = terminal_io::TerminalIO::new();
+    let mut lines = term.lines();
 
     let mut vm = VM::new(&mut term);
     vm.set_debugging(cli.debug);
+    if cli.raw_exceptions {
+        vm.set_exception_fallback(ExceptionFallback::Dispatch);
+    }
+    if cli.os {
+        vm.load_os()?;
+    }
+
+    for program in &cli.programs {
+        let ranges = if program == "-" {
+            vm.load_program(&stdin_program)
+        } else if program.ends_with(".asm") {
+            let source = fs::read_to_string(program).map_err(|e| format!("{}: {}", program, e))?;
+            let assembly = asm::assemble(program, &source)
+                .map_err(|e| format!("could not assemble {}:\n{}", program, e))?;
+            require_linked(program, &assembly)?;
+            vm.load_assembly(program, &source, &assembly)
+        } else {
+            vm.read_program(program)
+        }
+        .map_err(|e| format!("{}: {}", program, e))?;
+        if cli.debug {
+            for range in &ranges {
+                eprintln!("loaded {} at {}", program, loader::fmt_range(range));
+            }
+        }
+    }
+    for sym in &cli.symbols {
+        vm.load_symbols(sym)
+            .map_err(|e| format!("{}: {}", sym, e))?;
+    }
+    if let Some(entry) = cli.entry {
+        vm.registers_mut().set_pc(entry);
+    }
+    if cli.user_mode {
+        vm.enter_user_mode();
+    }
+
+    if cli.debugger {
+        terminal_io::catch_interrupts(true);
+        let mut debugger = Debugger::new();
+        debugger.repl(&mut vm, || lines.read_line(), &mut io::stdout())?;
+        return Ok(if debugger.finished() {
+            StopReason::Halted
+        } else {
+            StopReason::Condition
+        });
+    }
+
+    if let Some(addr) = &cli.gdb {
+        terminal_io::catch_interrupts(true);
+        let mut debugger = Debugger::new();
+        let end = serve_gdb(&mut debugger, &mut vm, addr)?;
+        if debugger.finished() {
+            return Ok(StopReason::Halted);
+        }
+        if end == GdbEnd::Killed {
+            return Ok(StopReason::Condition);
+        }
+        // gdb detached, so the program goes on by itself
+        terminal_io::catch_interrupts(false);
+    }
+
+    vm.execute().map_err(|e| {
+        let mut msg = e.to_string();
+        if let Some(label) = vm.symbolize(vm.last_pc()) {
+            msg.push_str(&format!(" (in {})", label));
+        }
+        if let Some(location) = vm.source_location(vm.last_pc()) {
+            msg.push_str(&format!("\n  --> {}", location));
+        }
+        msg.into()
+    })
+}
+
+/// Wait for a gdb remote protocol client to connect on `addr`, and serve it
+fn serve_gdb(debugger: &mut Debugger, vm: &mut VM, addr: &str) -> Result<GdbEnd, Box<dyn Error>> {
+    if addr.contains('/') {
+        // a socket left behind by an earlier run would make binding fail
+        if fs::metadata(addr).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(addr).map_err(|e| format!("{}: {}", addr, e))?;
+        }
+        let listener = UnixListener::bind(addr).map_err(|e| format!("{}: {}", addr, e))?;
+        eprintln!("waiting for a gdb remote protocol client on {}", addr);
+        let (stream, _) = listener.accept()?;
+        let end = debugger.serve_gdb(vm, stream.try_clone()?, stream);
+        let _ = fs::remove_file(addr);
+        return Ok(end?);
+    }
+
+    // a lone port is only opened locally
+    let addr = match addr.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => addr.to_string(),
+    };
+    let listener = TcpListener::bind(&addr).map_err(|e| format!("{}: {}", addr, e))?;
+    eprintln!(
+        "waiting for a gdb remote protocol client on {}",
+        listener.local_addr()?
+    );
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(debugger.serve_gdb(vm, stream.try_clone()?, stream)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // assemble `source` in a fresh directory with a listing, returning the result and the directory
+    fn assemble_with_listing(name: &str, source: &str) -> (bool, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("lc3-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prog.asm");
+        fs::write(&path, source).unwrap();
+
+        let result = assemble(AsmArgs {
+            source: path,
+            output: None,
+            lc3tools: false,
+            relocatable: false,
+            listing: true,
+        });
+        (result.is_ok(), dir)
+    }
+
+    #[test]
+    fn listing_is_written_with_the_object() {
+        let source = ".ORIG x3000\nHALT\n.END\n";
+        let (ok, dir) = assemble_with_listing("listing-ok", source);
+        assert!(ok);
+        let assembly = asm::assemble("prog.asm", source).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("prog.lst")).unwrap(),
+            asm::listing(&assembly, source)
+        );
+        assert!(dir.join("prog.obj").is_file());
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-    vm.read_program(&cli.program.expect("No program file given"));
-    vm.execute();
+    #[test]
+    fn no_listing_without_a_good_object() {
+        let sources = [
+            // needs linking
+            ".ORIG x3000\n.IMPORT PRINT\nJSR PRINT\nHALT\n.END\n",
+            // a plain object holds a single block
+            ".ORIG x3000\nHALT\n.END\n.ORIG x4000\nHALT\n.END\n",
+        ];
+        for (i, source) in sources.iter().enumerate() {
+            let (ok, dir) = assemble_with_listing(&format!("listing-bad{}", i), source);
+            assert!(!ok, "{}", source);
+            assert!(!dir.join("prog.lst").exists(), "{}", source);
+            assert!(!dir.join("prog.obj").exists(), "{}", source);
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
 }
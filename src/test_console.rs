@@ -0,0 +1,54 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// console for tests
+//////////////////////////////
+
+use crate::{DisplayIO, KeyboardIO};
+use std::collections::VecDeque;
+
+/// Gives keys from a fixed list, and records output
+#[derive(Default)]
+pub struct TestConsole {
+    pub keys: VecDeque<u8>,
+    // how many more times to say no key is ready, even if there is one
+    pub key_delay: usize,
+    pub output: Vec<u8>,
+}
+
+impl TestConsole {
+    pub fn with_keys(keys: &[u8]) -> TestConsole {
+        TestConsole {
+            keys: keys.iter().copied().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl KeyboardIO for TestConsole {
+    fn get_key(&mut self) -> Option<u8> {
+        self.keys.pop_front()
+    }
+    fn check_key(&mut self) -> bool {
+        if self.key_delay > 0 {
+            self.key_delay -= 1;
+            return false;
+        }
+        !self.keys.is_empty()
+    }
+}
+
+impl DisplayIO for TestConsole {
+    fn put_char(&mut self, c: u8) {
+        self.output.push(c);
+    }
+}
@@ -0,0 +1,138 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// errors and stop reasons
+//////////////////////////////
+
+use crate::vm::{loader, memory, Exception};
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+/// Why the VM could not load or keep running a program
+#[derive(Debug)]
+pub enum VmError {
+    /// Reading a program failed
+    Io(io::Error),
+    /// The object file is too short to even hold an origin
+    MissingOrigin,
+    /// The object file ends in the middle of a word
+    TruncatedObject { bytes: usize },
+    /// An lc3tools object file is malformed
+    BadLc3ToolsObject { offset: usize, reason: &'static str },
+    /// A textual image contains something other than text
+    NotText { format: loader::ObjectFormat },
+    /// A line of a textual image is not a word in its format
+    BadImageLine {
+        format: loader::ObjectFormat,
+        line: usize,
+        text: String,
+    },
+    /// An image runs past the end of memory at xFFFF
+    ImageWrapsAround { origin: u16, len: usize },
+    /// A line of a symbol table could not be understood
+    BadSymbolLine { line: usize, text: String },
+    /// An image would be written over the device registers
+    ImageInDeviceSpace { range: Range<usize> },
+    /// An image would overwrite part of one that was loaded before it
+    Overlap {
+        existing: Range<usize>,
+        new: Range<usize>,
+    },
+    /// An exception was raised, and no handler was installed for it
+    UnhandledException { exception: Exception, addr: u16 },
+    /// A device register address with no device behind it was accessed
+    UnmappedMmio { addr: u16 },
+    /// PC ran off the end of memory
+    PcOverflow,
+    /// There is no register with this index
+    InvalidRegister(u16),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Io(e) => write!(f, "could not read program: {}", e),
+            VmError::MissingOrigin => write!(f, "object file is too short to contain an origin"),
+            VmError::TruncatedObject { bytes } => write!(
+                f,
+                "object file is truncated: {} bytes is not a whole number of 16-bit words",
+                bytes
+            ),
+            VmError::BadLc3ToolsObject { offset, reason } => {
+                write!(f, "corrupt lc3tools object at byte {}: {}", offset, reason)
+            }
+            VmError::NotText { format } => write!(f, "{} image is not valid text", format),
+            VmError::BadImageLine { format, line, text } => {
+                write!(f, "line {}: `{}` is not a {} word", line, text, format)
+            }
+            VmError::ImageWrapsAround { origin, len } => write!(
+                f,
+                "image of {} words at x{:04X} runs past the end of memory",
+                len, origin
+            ),
+            VmError::BadSymbolLine { line, text } => {
+                write!(
+                    f,
+                    "symbol table line {}: expected `LABEL ADDR`, got `{}`",
+                    line, text
+                )
+            }
+            VmError::ImageInDeviceSpace { range } => write!(
+                f,
+                "image at {} overlaps the device registers at x{:04X}-xFFFF",
+                loader::fmt_range(range),
+                memory::DEVICE_SPACE
+            ),
+            VmError::Overlap { existing, new } => write!(
+                f,
+                "image at {} overlaps one already loaded at {}",
+                loader::fmt_range(new),
+                loader::fmt_range(existing)
+            ),
+            VmError::UnhandledException { exception, addr } => write!(
+                f,
+                "{} at {:#06x} with no handler installed",
+                exception, addr
+            ),
+            VmError::UnmappedMmio { addr } => {
+                write!(f, "no device register is mapped at {:#06x}", addr)
+            }
+            VmError::PcOverflow => write!(f, "PC ran past the end of memory"),
+            VmError::InvalidRegister(idx) => write!(f, "invalid register {}", idx),
+        }
+    }
+}
+
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VmError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(e: io::Error) -> Self {
+        VmError::Io(e)
+    }
+}
+
+/// Why the VM stopped running (without an error)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The clock was stopped through the MCR, usually by HALT
+    Halted,
+    /// The condition given to `VM::run_until` (or the step limit of `VM::run_for`) was met
+    Condition,
+}
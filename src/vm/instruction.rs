@@ -13,19 +13,46 @@ You should have received a copy of the GNU General Public License along with thi
 ////// instruction execution
 //////////////////////////////
 
-use crate::vm::VM;
-use std::io;
-use std::io::Write;
+use crate::vm::{memory, Exception, TrapMode, VmError, TRAP_VECTOR_TABLE, VM};
 
 ////////////////
 // Main part
 ////////////////
 
-pub fn get_instruction(vm: &mut VM) -> u16 {
-    return vm.mem.get_mem(vm.registers.pc);
+/// Look at the instruction at PC, without side effects
+pub fn get_instruction(vm: &VM) -> u16 {
+    vm.mem.peek(vm.registers.pc)
 }
 
+/// Fetch the instruction at `addr`, as the running program would
+pub fn fetch_instruction(vm: &mut VM, addr: u16) -> Result<u16, Fault> {
+    check_access(vm, addr)?;
+    Ok(vm.mem.fetch(addr)?)
+}
+
+/// Why an instruction could not complete
 #[derive(Debug)]
+pub enum Fault {
+    /// An exception the LC-3 itself should handle
+    Exception(Exception),
+    /// An error in the VM that stops execution
+    Error(VmError),
+}
+
+impl From<Exception> for Fault {
+    fn from(e: Exception) -> Self {
+        Fault::Exception(e)
+    }
+}
+
+impl From<VmError> for Fault {
+    fn from(e: VmError) -> Self {
+        Fault::Error(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum OpCode {
     // branch
     BR = 0,
@@ -43,7 +70,7 @@ pub enum OpCode {
     LDR,
     // store register
     STR,
-    // return from interrupt (unused)
+    // return from interrupt
     RTI,
     // bitwise not
     NOT,
@@ -63,6 +90,7 @@ pub enum OpCode {
     NOOP,
 }
 
+/// Decode the opcode of an instruction word
 pub fn get_opcode(instruction: u16) -> OpCode {
     // the opcode is stored in the left 4 bits
     match instruction >> 12 {
@@ -86,7 +114,7 @@ pub fn get_opcode(instruction: u16) -> OpCode {
     }
 }
 
-pub fn execute_instruction(vm: &mut VM, instr: u16) {
+pub fn execute_instruction(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let opcode = get_opcode(instr);
 
     match opcode {
@@ -98,12 +126,12 @@ pub fn execute_instruction(vm: &mut VM, instr: u16) {
         OpCode::AND => op_and(vm, instr),
         OpCode::LDR => op_ldr(vm, instr),
         OpCode::STR => op_str(vm, instr),
-        OpCode::RTI => unimplemented!("RTI (privilege violation)"),
+        OpCode::RTI => op_rti(vm, instr),
         OpCode::NOT => op_not(vm, instr),
         OpCode::LDI => op_ldi(vm, instr),
         OpCode::STI => op_sti(vm, instr),
         OpCode::JMP => op_jmp(vm, instr),
-        OpCode::RES => unimplemented!("RES (illegal instruction)"),
+        OpCode::RES => Err(Exception::IllegalOpcode.into()),
         OpCode::LEA => op_lea(vm, instr),
         OpCode::TRAP => op_trap(vm, instr),
         OpCode::NOOP => no_op(vm, instr),
@@ -115,7 +143,7 @@ pub fn execute_instruction(vm: &mut VM, instr: u16) {
 ////////////////
 
 /// Sign extend a value, given the amount of bits it currently has
-fn sign_extend(x: u16, bits: usize) -> u16 {
+pub(crate) fn sign_extend(x: u16, bits: usize) -> u16 {
     if (x >> (bits - 1) & 1) == 1 {
         x | (0xffff << bits)
     } else {
@@ -123,67 +151,103 @@ fn sign_extend(x: u16, bits: usize) -> u16 {
     }
 }
 
-fn no_op(vm: &mut VM, instr: u16) {}
+/// Check that the running program may access an address
+///
+/// In user mode, system space (below 0x3000) and the device registers (0xFE00 and up) are off-limits.
+fn check_access(vm: &VM, addr: u16) -> Result<(), Exception> {
+    if vm.registers.user_mode() && !(0x3000..0xFE00).contains(&addr) {
+        Err(Exception::AccessViolation)
+    } else {
+        Ok(())
+    }
+}
+
+/// Read memory on behalf of the running program
+fn load(vm: &mut VM, addr: u16) -> Result<u16, Fault> {
+    check_access(vm, addr)?;
+    Ok(vm.mem.get_mem(addr)?)
+}
+
+/// Write memory on behalf of the running program
+fn store(vm: &mut VM, addr: u16, val: u16) -> Result<(), Fault> {
+    check_access(vm, addr)?;
+    vm.mem.set_mem(addr, val)?;
+    Ok(())
+}
+
+fn no_op(vm: &mut VM, instr: u16) -> Result<(), Fault> {
+    Ok(())
+}
 
 ////////////////
 // Load ops
 ////////////////
 
-fn op_lea(vm: &mut VM, instr: u16) {
+fn op_lea(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let dr = (instr >> 9) & 0b111;
     let offset = sign_extend(instr & 0x1ff, 9);
 
     vm.registers
-        .set_reg_with_cond(dr, vm.registers.pc.wrapping_add(offset));
+        .set_reg_with_cond(dr, vm.registers.pc.wrapping_add(offset))?;
+
+    Ok(())
 }
 
-fn op_ld(vm: &mut VM, instr: u16) {
+fn op_ld(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let dr = (instr >> 9) & 0b111;
     let offset = sign_extend(instr & 0x1ff, 9);
 
-    vm.registers
-        .set_reg_with_cond(dr, vm.mem.get_mem(vm.registers.pc.wrapping_add(offset)));
+    let val = load(vm, vm.registers.pc.wrapping_add(offset))?;
+    vm.registers.set_reg_with_cond(dr, val)?;
+
+    Ok(())
 }
 
-fn op_ldi(vm: &mut VM, instr: u16) {
+fn op_ldi(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let dr = (instr >> 9) & 0b111;
     let offset = sign_extend(instr & 0x1ff, 9);
-    let indirect = vm.mem.get_mem(vm.registers.pc.wrapping_add(offset));
+    let indirect = load(vm, vm.registers.pc.wrapping_add(offset))?;
+
+    let val = load(vm, indirect)?;
+    vm.registers.set_reg_with_cond(dr, val)?;
 
-    vm.registers.set_reg_with_cond(dr, vm.mem.get_mem(indirect));
+    Ok(())
 }
 
-fn op_ldr(vm: &mut VM, instr: u16) {
+fn op_ldr(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let dr = (instr >> 9) & 0b111;
     let base_r = (instr >> 6) & 0b111;
     let offset = sign_extend(instr & 0x3f, 6);
 
-    let addr = vm.registers.get_reg(base_r).wrapping_add(offset);
-    vm.registers.set_reg_with_cond(dr, vm.mem.get_mem(addr));
+    let addr = vm.registers.get_reg(base_r)?.wrapping_add(offset);
+    let val = load(vm, addr)?;
+    vm.registers.set_reg_with_cond(dr, val)?;
+
+    Ok(())
 }
 
 ////////////////
 // Jumps/branches
 ////////////////
 
-fn op_jsr(vm: &mut VM, instr: u16) {
+fn op_jsr(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     // this function also includes JSRR
-    vm.registers.r7 = vm.registers.pc;
+    vm.registers.set_reg(7, vm.registers.pc)?;
 
     if (instr >> 11) & 1 == 0 {
         let base_r = (instr >> 6) & 0b111;
-        vm.registers.pc = vm.registers.get_reg(base_r);
+        vm.registers.pc = vm.registers.get_reg(base_r)?;
     } else {
         let offset = sign_extend(instr & 0x7ff, 11);
         vm.registers.pc = vm.registers.pc.wrapping_add(offset);
     }
+
+    Ok(())
 }
 
-fn op_br(vm: &mut VM, instr: u16) {
+fn op_br(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let offset = sign_extend(instr & 0x1ff, 9);
-    // technically the COND we have is just a part of the PSR register in the spec
-    // therefore isolate the last 3 bits
-    let cond = vm.registers.cond & 0x7;
+    let cond = vm.registers.cond();
 
     let need_cond = (instr >> 9) & 0x7;
 
@@ -193,37 +257,57 @@ fn op_br(vm: &mut VM, instr: u16) {
     if need_cond & cond != 0 || need_cond == 0b111 {
         vm.registers.pc = vm.registers.pc.wrapping_add(offset);
     }
+
+    Ok(())
 }
 
-fn op_jmp(vm: &mut VM, instr: u16) {
+fn op_jmp(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     // RET is a special case of this where BaseR is R7
     let base_r = (instr >> 6) & 0b111;
 
-    vm.registers.pc = vm.registers.get_reg(base_r);
+    vm.registers.pc = vm.registers.get_reg(base_r)?;
+
+    Ok(())
+}
+
+fn op_rti(vm: &mut VM, instr: u16) -> Result<(), Fault> {
+    if vm.registers.user_mode() {
+        return Err(Exception::PrivilegeViolation.into());
+    }
+
+    // the interrupted program's PC and PSR were pushed onto the supervisor stack
+    vm.registers.pc = vm.pop_stack()?;
+    let psr = vm.pop_stack()?;
+    vm.registers.restore_psr(psr);
+
+    Ok(())
 }
 
 ////////////////
 // Store ops
 ////////////////
 
-fn op_st(vm: &mut VM, instr: u16) {
+fn op_st(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let sr = (instr >> 9) & 0b111;
     let offset = sign_extend(instr & 0x1ff, 9);
-    vm.mem.set_mem(
-        vm.registers.pc.wrapping_add(offset),
-        vm.registers.get_reg(sr),
-    );
+    let val = vm.registers.get_reg(sr)?;
+    store(vm, vm.registers.pc.wrapping_add(offset), val)?;
+
+    Ok(())
 }
 
-fn op_sti(vm: &mut VM, instr: u16) {
+fn op_sti(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let sr = (instr >> 9) & 0b111;
     let offset = sign_extend(instr & 0x1ff, 9);
 
-    let addr = vm.mem.get_mem(vm.registers.pc.wrapping_add(offset));
-    vm.mem.set_mem(addr, vm.registers.get_reg(sr));
+    let addr = load(vm, vm.registers.pc.wrapping_add(offset))?;
+    let val = vm.registers.get_reg(sr)?;
+    store(vm, addr, val)?;
+
+    Ok(())
 }
 
-fn op_str(vm: &mut VM, instr: u16) {
+fn op_str(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let sr = (instr >> 9) & 0b111;
     let base_r = (instr >> 6) & 0b111;
     let offset = sign_extend(instr & 0x3f, 6);
@@ -235,15 +319,18 @@ fn op_str(vm: &mut VM, instr: u16) {
     //
     // apparently narrowing casts automatically wrap integers
     // this is more explicit
-    let addr = vm.registers.get_reg(base_r).wrapping_add(offset);
-    vm.mem.set_mem(addr, vm.registers.get_reg(sr));
+    let addr = vm.registers.get_reg(base_r)?.wrapping_add(offset);
+    let val = vm.registers.get_reg(sr)?;
+    store(vm, addr, val)?;
+
+    Ok(())
 }
 
 ////////////////
 // Arithmetic
 ////////////////
 
-fn op_add(vm: &mut VM, instr: u16) {
+fn op_add(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let dr = (instr >> 9) & 0b111;
     let sr1 = (instr >> 6) & 0b111;
 
@@ -252,114 +339,158 @@ fn op_add(vm: &mut VM, instr: u16) {
 
         let res = vm
             .registers
-            .get_reg(sr1)
-            .wrapping_add(vm.registers.get_reg(sr2));
-        vm.registers.set_reg_with_cond(dr, res);
+            .get_reg(sr1)?
+            .wrapping_add(vm.registers.get_reg(sr2)?);
+        vm.registers.set_reg_with_cond(dr, res)?;
     } else {
         let imm = sign_extend(instr & 0x1f, 5);
 
-        let res = vm.registers.get_reg(sr1).wrapping_add(imm);
-        vm.registers.set_reg_with_cond(dr, res);
+        let res = vm.registers.get_reg(sr1)?.wrapping_add(imm);
+        vm.registers.set_reg_with_cond(dr, res)?;
     }
+
+    Ok(())
 }
 
-fn op_and(vm: &mut VM, instr: u16) {
+fn op_and(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let dr = (instr >> 9) & 0b111;
     let sr1 = (instr >> 6) & 0b111;
 
     if (instr >> 5) & 1 == 0 {
         let sr2 = instr & 0b111;
 
-        let res = vm.registers.get_reg(sr1) & vm.registers.get_reg(sr2);
-        vm.registers.set_reg_with_cond(dr, res);
+        let res = vm.registers.get_reg(sr1)? & vm.registers.get_reg(sr2)?;
+        vm.registers.set_reg_with_cond(dr, res)?;
     } else {
         let imm = sign_extend(instr & 0x1f, 5);
 
-        let res = vm.registers.get_reg(sr1) & imm;
-        vm.registers.set_reg_with_cond(dr, res);
+        let res = vm.registers.get_reg(sr1)? & imm;
+        vm.registers.set_reg_with_cond(dr, res)?;
     }
+
+    Ok(())
 }
 
-fn op_not(vm: &mut VM, instr: u16) {
+fn op_not(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let dr = (instr >> 9) & 0b111;
     let sr = (instr >> 6) & 0b111;
 
     // NOTE
     // rustc is very friendly and tells you off if you use ~ as bitwise not
-    let res = !vm.registers.get_reg(sr);
-    vm.registers.set_reg_with_cond(dr, res);
+    let res = !vm.registers.get_reg(sr)?;
+    vm.registers.set_reg_with_cond(dr, res)?;
+
+    Ok(())
 }
 
 ////////////////
 // Trap/trap routines
 ////////////////
 
-fn op_trap(vm: &mut VM, instr: u16) {
-    // conform to spec
-    // (we don't actually need it in this implementation)
-    vm.registers.r7 = vm.registers.pc;
+/// Natively emulated service routine
+type TrapRoutine = fn(&mut VM) -> Result<(), VmError>;
 
+fn op_trap(vm: &mut VM, instr: u16) -> Result<(), Fault> {
     let trap_vector = instr & 0xff;
-    match trap_vector {
-        0x20 => trap_getc(vm),
-        0x21 => trap_out(vm),
-        0x22 => trap_puts(vm),
-        0x23 => trap_in(vm),
-        0x24 => trap_putsp(vm),
-        0x25 => vm.running = false,
-        _ => unimplemented!(),
+    vm.trap_taken = Some(trap_vector as u8);
+
+    if vm.trap_mode == TrapMode::Native {
+        let routine: Option<TrapRoutine> = match trap_vector {
+            0x20 => Some(trap_getc),
+            0x21 => Some(trap_out),
+            0x22 => Some(trap_puts),
+            0x23 => Some(trap_in),
+            0x24 => Some(trap_putsp),
+            0x25 => Some(trap_halt),
+            _ => None,
+        };
+
+        if let Some(routine) = routine {
+            // conform to spec
+            // (we don't actually need it in this implementation)
+            vm.registers.set_reg(7, vm.registers.pc)?;
+            routine(vm)?;
+            return Ok(());
+        }
+
+        // we have no service routine for this vector, unless the program installed one
+        if vm.mem.get_mem(TRAP_VECTOR_TABLE + trap_vector)? == 0 {
+            return Err(Exception::IllegalOpcode.into());
+        }
+    }
+
+    // the routine runs in supervisor mode at the caller's priority, and returns with RTI
+    vm.dispatch(TRAP_VECTOR_TABLE + trap_vector, vm.registers.priority())?;
+
+    Ok(())
+}
+
+/// Poll a status register (KBSR or DSR) until its device is ready
+///
+/// Only the first read is logged, so that a long wait does not pile up reads in the step outcome.
+fn wait_ready(vm: &mut VM, status: u16) -> Result<(), VmError> {
+    let mut val = vm.mem.get_mem(status)?;
+    while val & memory::STATUS_READY == 0 {
+        val = vm.mem.fetch(status)?;
     }
+    Ok(())
 }
 
-fn trap_puts(vm: &mut VM) {
+/// Write a character to the display through its device registers
+fn display_char(vm: &mut VM, c: u16) -> Result<(), VmError> {
+    wait_ready(vm, memory::DSR)?;
+    vm.mem.set_mem(memory::DDR, c & 0xFF)
+}
+
+fn trap_puts(vm: &mut VM) -> Result<(), VmError> {
     let mut idx = vm.registers.r0;
     loop {
-        let c = vm.mem.get_mem(idx) as u8 as char;
-        if c == '\0' {
+        let c = vm.mem.get_mem(idx)? & 0xFF;
+        if c == 0 {
             break;
         }
 
-        print!("{}", c);
-        idx += 1;
+        display_char(vm, c)?;
+        idx = idx.wrapping_add(1);
     }
-    let _ = io::stdout().flush();
+    Ok(())
 }
 
-fn trap_putsp(vm: &mut VM) {
+fn trap_putsp(vm: &mut VM) -> Result<(), VmError> {
     let mut idx = vm.registers.r0;
 
     'iter: loop {
-        for mask in [0xFF, 0xFF00] {
-            let c = (vm.mem.get_mem(idx) & mask) as u8 as char;
-            if c == '\0' {
+        // two characters are packed per word, starting with the low byte
+        for shift in [0, 8] {
+            let c = (vm.mem.get_mem(idx)? >> shift) & 0xFF;
+            if c == 0 {
                 break 'iter;
             }
 
-            print!("{}", c);
+            display_char(vm, c)?;
         }
-        idx += 1;
+        idx = idx.wrapping_add(1);
     }
-    let _ = io::stdout().flush();
+    Ok(())
 }
 
-fn trap_getc(vm: &mut VM) {
-    while vm.mem.get_mem(0xFE00) & (1 << 15) == 0 {}
-    vm.registers.r0 = vm.mem.get_mem(0xFE02) & 0xFF;
+fn trap_getc(vm: &mut VM) -> Result<(), VmError> {
+    wait_ready(vm, memory::KBSR)?;
+    let key = vm.mem.get_mem(memory::KBDR)? & 0xFF;
+    vm.registers.set_reg(0, key)?;
+    Ok(())
 }
 
-fn trap_in(vm: &mut VM) {
-    trap_getc(vm);
+fn trap_in(vm: &mut VM) -> Result<(), VmError> {
+    trap_getc(vm)?;
     // echo character
-    trap_out(vm);
+    trap_out(vm)
 }
 
-fn trap_out(vm: &mut VM) {
-    let val = char::from_u32((vm.registers.r0 & 0xFF).into());
-    match val {
-        Some(c) => {
-            let _ = io::stdout().flush();
-            print!("{}", c);
-        }
-        None => {}
-    }
+fn trap_out(vm: &mut VM) -> Result<(), VmError> {
+    display_char(vm, vm.registers.r0)
+}
+
+fn trap_halt(vm: &mut VM) -> Result<(), VmError> {
+    vm.halt()
 }
@@ -0,0 +1,561 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// object file loading
+//////////////////////////////
+
+use crate::vm::{memory, number, VmError};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::{fmt, str};
+
+/// Encoding of a program image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFormat {
+    /// Big-endian binary words, the first being the origin (`.obj`)
+    Obj,
+    /// One hexadecimal word per line, the first being the origin (`.hex`)
+    Hex,
+    /// One 16-digit binary word per line, the first being the origin (`.bin`)
+    Bin,
+    /// Object files written by lc3tools, which may hold several sections (`.obj`)
+    Lc3Tools,
+}
+
+/// Start of every lc3tools object file
+pub const LC3TOOLS_MAGIC: &[u8] = &[0x1c, 0x30, 0x15, 0xc0, 0x01];
+/// Version of the lc3tools object format that is read and written
+pub const LC3TOOLS_VERSION: &[u8] = &[0x01, 0x01];
+
+impl ObjectFormat {
+    /// Format implied by a file extension
+    ///
+    /// Both kinds of object file end in `.obj`, so `Obj` only means "one of the two".
+    pub fn from_extension(path: impl AsRef<Path>) -> Option<ObjectFormat> {
+        let ext = path.as_ref().extension()?.to_str()?;
+        match ext.to_ascii_lowercase().as_str() {
+            "obj" => Some(ObjectFormat::Obj),
+            "hex" => Some(ObjectFormat::Hex),
+            "bin" => Some(ObjectFormat::Bin),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the contents of an image
+    ///
+    /// Files starting with the lc3tools magic number are lc3tools objects.
+    /// Text where every line is a binary (or hex) word is taken as `.bin` (or `.hex`);
+    /// anything else is a binary object.
+    pub fn detect(bytes: &[u8]) -> ObjectFormat {
+        if bytes.starts_with(LC3TOOLS_MAGIC) {
+            return ObjectFormat::Lc3Tools;
+        }
+        let Ok(text) = str::from_utf8(bytes) else {
+            return ObjectFormat::Obj;
+        };
+        let mut lines = text_lines(text).peekable();
+        if lines.peek().is_none() {
+            return ObjectFormat::Obj;
+        }
+
+        let lines: Vec<&str> = lines.map(|(_, line)| line).collect();
+        if lines.iter().all(|line| parse_bin(line).is_some()) {
+            ObjectFormat::Bin
+        } else if lines.iter().all(|line| parse_hex(line).is_some()) {
+            ObjectFormat::Hex
+        } else {
+            ObjectFormat::Obj
+        }
+    }
+}
+
+impl fmt::Display for ObjectFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectFormat::Obj => write!(f, "object"),
+            ObjectFormat::Hex => write!(f, "hex"),
+            ObjectFormat::Bin => write!(f, "binary"),
+            ObjectFormat::Lc3Tools => write!(f, "lc3tools object"),
+        }
+    }
+}
+
+/// A block of words placed contiguously in memory, starting at `origin`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl Segment {
+    /// Addresses covered by the segment (the end is exclusive, so it can be 0x10000)
+    pub fn range(&self) -> Range<usize> {
+        let start = self.origin as usize;
+        start..start + self.words.len()
+    }
+
+    /// Check that the segment fits in memory below the device registers
+    pub fn validate(&self) -> Result<(), VmError> {
+        let range = self.range();
+        if range.end > memory::MEM_SIZE {
+            return Err(VmError::ImageWrapsAround {
+                origin: self.origin,
+                len: self.words.len(),
+            });
+        }
+        if range.end > memory::DEVICE_SPACE as usize {
+            return Err(VmError::ImageInDeviceSpace { range });
+        }
+        Ok(())
+    }
+}
+
+/// Format an address range the way LC-3 tools do, e.g. `x3000-x30FF`
+pub fn fmt_range(range: &Range<usize>) -> String {
+    if range.is_empty() {
+        format!("x{:04X} (empty)", range.start)
+    } else {
+        format!("x{:04X}-x{:04X}", range.start, range.end - 1)
+    }
+}
+
+/// Read a program file, in the format given by its extension or else by its contents
+pub fn read_file(path: impl AsRef<Path>) -> Result<Vec<Segment>, VmError> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)?;
+    let format = match ObjectFormat::from_extension(path) {
+        // lc3tools objects share the extension
+        Some(ObjectFormat::Obj) if bytes.starts_with(LC3TOOLS_MAGIC) => ObjectFormat::Lc3Tools,
+        Some(format) => format,
+        None => ObjectFormat::detect(&bytes),
+    };
+    read_image(&bytes, format)
+}
+
+/// Parse a program image in the given format, into its sections
+pub fn read_image(bytes: &[u8], format: ObjectFormat) -> Result<Vec<Segment>, VmError> {
+    match format {
+        ObjectFormat::Obj => Ok(vec![read_obj(bytes)?]),
+        ObjectFormat::Hex => Ok(vec![read_text(bytes, format, parse_hex)?]),
+        ObjectFormat::Bin => Ok(vec![read_text(bytes, format, parse_bin)?]),
+        ObjectFormat::Lc3Tools => read_lc3tools(bytes),
+    }
+}
+
+/// Parse an object image: the origin, followed by the words to load there
+pub fn read_obj(mut f: impl Read) -> Result<Segment, VmError> {
+    // NOTE
+    // LC-3 works with 16-bit words, in big endian.
+    // Endianness is the order of bytes *within a word*.
+    // Big endian is the "natural" order (left to right, start with most significant byte).
+    // This means that 0x3000 is encoded as 30 00.
+    // Little endian is commonly used on processors (see why at https://softwareengineering.stackexchange.com/questions/95556).
+    // For example, 0x12345678 (if we assume 32-bit words) would be encoded as 78 56 34 12.
+    // It turns out that the `hexdump` command uses 16-bit words and little-endian by default
+    // (at least on my machine).
+    // Therefore, it flips each pair of bytes.
+    // Meanwhile, `hed` uses big-endian.
+    // To make hexdump ignore words, pass the `-C` flag for a byte-by-byte output.
+    let mut bytes = Vec::new();
+    f.read_to_end(&mut bytes)?;
+
+    if bytes.len() < 2 {
+        return Err(VmError::MissingOrigin);
+    }
+    if bytes.len() % 2 != 0 {
+        return Err(VmError::TruncatedObject { bytes: bytes.len() });
+    }
+
+    let mut words = bytes.chunks_exact(2).map(BigEndian::read_u16);
+    let origin = words.next().unwrap_or_default();
+
+    Ok(Segment {
+        origin,
+        words: words.collect(),
+    })
+}
+
+// non-empty lines of a textual image with their line numbers, ignoring `;` comments
+fn text_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.split(';').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+/// Parse a textual image, one word per line, the first being the origin
+fn read_text(
+    bytes: &[u8],
+    format: ObjectFormat,
+    parse: fn(&str) -> Option<u16>,
+) -> Result<Segment, VmError> {
+    let text = str::from_utf8(bytes).map_err(|_| VmError::NotText { format })?;
+
+    let mut words = Vec::new();
+    for (line, content) in text_lines(text) {
+        let word = parse(content).ok_or_else(|| VmError::BadImageLine {
+            format,
+            line,
+            text: content.to_string(),
+        })?;
+        words.push(word);
+    }
+
+    if words.is_empty() {
+        return Err(VmError::MissingOrigin);
+    }
+    let origin = words.remove(0);
+    Ok(Segment { origin, words })
+}
+
+// a word of up to four hex digits, optionally prefixed with `x` or `0x`
+fn parse_hex(s: &str) -> Option<u16> {
+    match number::split_radix(s, 16) {
+        (digits, 16) if digits.len() <= 4 && !digits.starts_with('-') => number::parse_word(s, 16),
+        _ => None,
+    }
+}
+
+// a word of exactly sixteen binary digits
+fn parse_bin(s: &str) -> Option<u16> {
+    if s.len() != 16 {
+        return None;
+    }
+    u16::from_str_radix(s, 2).ok()
+}
+
+////////////////
+// lc3tools objects
+////////////////
+
+// NOTE
+// After the magic number and version, an lc3tools object is a list of entries, each being
+//   - the word (little endian)
+//   - a flag byte, set if the word is an origin starting a new section rather than data
+//   - the length of the source line the word came from (32-bit little endian)
+//   - that source line
+// lc3tools writes these straight from memory, hence the little endian.
+
+/// Parse an lc3tools object into its sections
+pub fn read_lc3tools(bytes: &[u8]) -> Result<Vec<Segment>, VmError> {
+    let corrupt =
+        |offset: usize, reason: &'static str| VmError::BadLc3ToolsObject { offset, reason };
+
+    let Some(rest) = bytes.strip_prefix(LC3TOOLS_MAGIC) else {
+        return Err(corrupt(0, "missing magic number"));
+    };
+    let Some(mut rest) = rest.strip_prefix(LC3TOOLS_VERSION) else {
+        return Err(corrupt(LC3TOOLS_MAGIC.len(), "unsupported format version"));
+    };
+
+    let mut segments: Vec<Segment> = Vec::new();
+    while !rest.is_empty() {
+        let offset = bytes.len() - rest.len();
+        if rest.len() < 7 {
+            return Err(corrupt(offset, "truncated entry"));
+        }
+
+        let word = LittleEndian::read_u16(&rest[0..2]);
+        let is_orig = rest[2] != 0;
+        let line_len = LittleEndian::read_u32(&rest[3..7]) as usize;
+        rest = &rest[7..];
+        if rest.len() < line_len {
+            return Err(corrupt(offset, "truncated source line"));
+        }
+        rest = &rest[line_len..];
+
+        if is_orig {
+            segments.push(Segment {
+                origin: word,
+                words: Vec::new(),
+            });
+        } else {
+            match segments.last_mut() {
+                Some(segment) => segment.words.push(word),
+                None => return Err(corrupt(offset, "data before the first origin")),
+            }
+        }
+    }
+
+    if segments.is_empty() {
+        return Err(VmError::MissingOrigin);
+    }
+    Ok(segments)
+}
+
+/// Write sections as an lc3tools object
+///
+/// This format records the source line of every word; `lines` is asked for each address,
+/// and may return an empty string when there is nothing to record.
+pub fn write_lc3tools(
+    segments: &[Segment],
+    mut lines: impl FnMut(u16) -> String,
+    mut w: impl Write,
+) -> io::Result<()> {
+    w.write_all(LC3TOOLS_MAGIC)?;
+    w.write_all(LC3TOOLS_VERSION)?;
+
+    for segment in segments {
+        write_lc3tools_entry(&mut w, segment.origin, true, "")?;
+        for (addr, &word) in segment.range().zip(segment.words.iter()) {
+            write_lc3tools_entry(&mut w, word, false, &lines(addr as u16))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_lc3tools_entry(
+    w: &mut impl Write,
+    word: u16,
+    is_orig: bool,
+    line: &str,
+) -> io::Result<()> {
+    w.write_u16::<LittleEndian>(word)?;
+    w.write_u8(is_orig as u8)?;
+    w.write_u32::<LittleEndian>(line.len() as u32)?;
+    w.write_all(line.as_bytes())
+}
+
+/// Write a section as a plain object: the origin, then big-endian words
+pub fn write_obj(segment: &Segment, mut w: impl Write) -> io::Result<()> {
+    w.write_u16::<BigEndian>(segment.origin)?;
+    for &word in &segment.words {
+        w.write_u16::<BigEndian>(word)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_console::TestConsole;
+    use crate::VM;
+
+    fn segment(origin: u16, words: &[u16]) -> Segment {
+        Segment {
+            origin,
+            words: words.to_vec(),
+        }
+    }
+
+    #[test]
+    fn obj_round_trip() {
+        let original = segment(0x3000, &[0x1234, 0xF025, 0]);
+        let mut bytes = Vec::new();
+        write_obj(&original, &mut bytes).unwrap();
+        assert_eq!(bytes, [0x30, 0x00, 0x12, 0x34, 0xF0, 0x25, 0x00, 0x00]);
+        assert_eq!(ObjectFormat::detect(&bytes), ObjectFormat::Obj);
+        assert_eq!(read_obj(&bytes[..]).unwrap(), original);
+
+        // an origin alone is an empty program
+        assert_eq!(read_obj(&[0x40, 0x00][..]).unwrap(), segment(0x4000, &[]));
+    }
+
+    #[test]
+    fn truncated_obj() {
+        assert!(matches!(read_obj(&[][..]), Err(VmError::MissingOrigin)));
+        assert!(matches!(read_obj(&[0x30][..]), Err(VmError::MissingOrigin)));
+        assert!(matches!(
+            read_obj(&[0x30, 0x00, 0x12][..]),
+            Err(VmError::TruncatedObject { bytes: 3 })
+        ));
+    }
+
+    #[test]
+    fn segments_must_fit_below_the_devices() {
+        assert!(segment(0xFDFF, &[1]).validate().is_ok());
+        assert!(segment(0xFE00, &[]).validate().is_ok());
+        assert!(matches!(
+            segment(0xFDFF, &[1, 2]).validate(),
+            Err(VmError::ImageInDeviceSpace { range }) if range == (0xFDFF..0xFE01)
+        ));
+        assert!(matches!(
+            segment(0xFFFF, &[1, 2]).validate(),
+            Err(VmError::ImageWrapsAround {
+                origin: 0xFFFF,
+                len: 2
+            })
+        ));
+        assert_eq!(fmt_range(&segment(0x3000, &[1, 2]).range()), "x3000-x3001");
+        assert_eq!(fmt_range(&segment(0x3000, &[]).range()), "x3000 (empty)");
+    }
+
+    #[test]
+    fn overlapping_segments_are_refused() {
+        let mut console = TestConsole::default();
+        let mut vm = VM::new(&mut console);
+        vm.load_segment(&segment(0x3000, &[1, 2, 3])).unwrap();
+        // touching is fine
+        vm.load_segment(&segment(0x3003, &[4])).unwrap();
+        vm.load_segment(&segment(0x2FFF, &[5])).unwrap();
+
+        let err = vm.load_segment(&segment(0x3002, &[6, 7])).unwrap_err();
+        assert!(matches!(
+            &err,
+            VmError::Overlap { existing, new } if *existing == (0x3000..0x3003) && *new == (0x3002..0x3004)
+        ));
+        assert_eq!(
+            err.to_string(),
+            "image at x3002-x3003 overlaps one already loaded at x3000-x3002"
+        );
+        // nothing was written
+        assert_eq!(vm.peek_mem(0x3002), 3);
+
+        assert!(matches!(
+            vm.load_segment(&segment(0xFFFE, &[1, 2, 3])),
+            Err(VmError::ImageWrapsAround { .. })
+        ));
+    }
+
+    #[test]
+    fn hex_and_bin_round_trips() {
+        let original = segment(0x3000, &[0x1234, 0xF025, 0, 0xB800]);
+        let hex: String = std::iter::once(original.origin)
+            .chain(original.words.iter().copied())
+            .map(|word| format!("{:04X}\n", word))
+            .collect();
+        let bin: String = std::iter::once(original.origin)
+            .chain(original.words.iter().copied())
+            .map(|word| format!("{:016b}\n", word))
+            .collect();
+
+        assert_eq!(ObjectFormat::detect(hex.as_bytes()), ObjectFormat::Hex);
+        assert_eq!(ObjectFormat::detect(bin.as_bytes()), ObjectFormat::Bin);
+        assert_eq!(
+            read_image(hex.as_bytes(), ObjectFormat::Hex).unwrap(),
+            std::slice::from_ref(&original)
+        );
+        assert_eq!(
+            read_image(bin.as_bytes(), ObjectFormat::Bin).unwrap(),
+            [original]
+        );
+    }
+
+    #[test]
+    fn text_images() {
+        let hex = "; a comment\n\nx3000\n  0x12ab ; trailing comment\nb800\nF\n";
+        assert_eq!(
+            read_image(hex.as_bytes(), ObjectFormat::Hex).unwrap(),
+            [segment(0x3000, &[0x12AB, 0xB800, 0xF])]
+        );
+
+        let bad_line = |text: &str, format| match read_image(text.as_bytes(), format) {
+            Err(VmError::BadImageLine { line, text, .. }) => (line, text),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            bad_line("3000\n\n12345\n", ObjectFormat::Hex),
+            (3, "12345".to_string())
+        );
+        assert_eq!(
+            bad_line("3000\n-1\n", ObjectFormat::Hex),
+            (2, "-1".to_string())
+        );
+        assert_eq!(
+            bad_line("0011000000000000\n101\n", ObjectFormat::Bin),
+            (2, "101".to_string())
+        );
+
+        assert!(matches!(
+            read_image(b"; nothing\n", ObjectFormat::Hex),
+            Err(VmError::MissingOrigin)
+        ));
+        assert!(matches!(
+            read_image(&[0x30, 0xFF], ObjectFormat::Hex),
+            Err(VmError::NotText {
+                format: ObjectFormat::Hex
+            })
+        ));
+        // sixteen digits of 0 and 1 are read as binary rather than hex
+        assert_eq!(
+            ObjectFormat::detect(b"0011000000000000\n"),
+            ObjectFormat::Bin
+        );
+        assert_eq!(
+            ObjectFormat::detect(b"3000\nnot a word\n"),
+            ObjectFormat::Obj
+        );
+    }
+
+    // an lc3tools object with one section at x3000 holding xF025
+    fn lc3tools_object() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_lc3tools(&[segment(0x3000, &[0xF025])], |_| "HALT".into(), &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn lc3tools_round_trip() {
+        let original = [
+            segment(0x3000, &[0x1234, 0xF025]),
+            segment(0x4000, &[]),
+            segment(0x5000, &[7]),
+        ];
+        let mut bytes = Vec::new();
+        write_lc3tools(
+            &original,
+            |addr| format!("line at x{:04X}", addr),
+            &mut bytes,
+        )
+        .unwrap();
+
+        assert_eq!(ObjectFormat::detect(&bytes), ObjectFormat::Lc3Tools);
+        assert_eq!(
+            read_image(&bytes, ObjectFormat::Lc3Tools).unwrap(),
+            original
+        );
+
+        assert_eq!(
+            lc3tools_object(),
+            [
+                &[0x1c, 0x30, 0x15, 0xc0, 0x01, 0x01, 0x01][..],
+                // origin, with no source line
+                &[0x00, 0x30, 1, 0, 0, 0, 0],
+                // a word and its line
+                &[0x25, 0xF0, 0, 4, 0, 0, 0],
+                b"HALT",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn corrupt_lc3tools_objects() {
+        let reason = |bytes: &[u8]| match read_lc3tools(bytes) {
+            Err(VmError::BadLc3ToolsObject { offset, reason }) => (offset, reason),
+            other => panic!("{:?}", other),
+        };
+        let good = lc3tools_object();
+
+        assert_eq!(reason(&good[1..]), (0, "missing magic number"));
+        let mut version = good.clone();
+        version[6] = 2;
+        assert_eq!(reason(&version), (5, "unsupported format version"));
+        // cut in the middle of the second entry's header, then of its line
+        assert_eq!(reason(&good[..16]), (14, "truncated entry"));
+        assert_eq!(
+            reason(&good[..good.len() - 1]),
+            (14, "truncated source line")
+        );
+        // a data word with no origin before it
+        let mut no_origin = good.clone();
+        no_origin[9] = 0;
+        assert_eq!(reason(&no_origin), (7, "data before the first origin"));
+
+        assert!(matches!(
+            read_lc3tools(&good[..7]),
+            Err(VmError::MissingOrigin)
+        ));
+    }
+}
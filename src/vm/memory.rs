@@ -17,47 +17,211 @@ You should have received a copy of the GNU General Public License along with thi
 // memory interface
 ////////////////
 
-use super::terminal_io;
+use super::{terminal_io, MemRead, MemWrite, VmError};
 
+/// Number of addressable words
 pub const MEM_SIZE: usize = 1 << 16;
 
+/// Keyboard status register
+pub const KBSR: u16 = 0xFE00;
+/// Keyboard data register
+pub const KBDR: u16 = 0xFE02;
+
+/// Display status register
+pub const DSR: u16 = 0xFE04;
+/// Display data register
+pub const DDR: u16 = 0xFE06;
+
+/// Machine control register
+pub const MCR: u16 = 0xFFFE;
+
+// start of the device register page
+pub const DEVICE_SPACE: u16 = 0xFE00;
+
+// KBSR/DSR bits
+pub const STATUS_READY: u16 = 1 << 15;
+const KBSR_IE: u16 = 1 << 14;
+
+// MCR bits
+pub const MCR_CLOCK_ENABLE: u16 = 1 << 15;
+
 pub struct Memory<'a> {
     data: [u16; MEM_SIZE],
-    io: &'a mut dyn terminal_io::KeyboardIO,
+    io: &'a mut dyn terminal_io::ConsoleIO,
+    // accesses since the log was last taken
+    reads: Vec<MemRead>,
+    writes: Vec<MemWrite>,
 }
 
 impl Memory<'_> {
-    pub fn new(keyboard_io: &mut dyn terminal_io::KeyboardIO) -> Memory {
+    pub fn new(console_io: &mut dyn terminal_io::ConsoleIO) -> Memory<'_> {
+        let mut data = [0; MEM_SIZE];
+        // the clock runs from power-on
+        data[MCR as usize] = MCR_CLOCK_ENABLE;
+
         Memory {
-            data: [0; MEM_SIZE],
-            io: keyboard_io,
+            data,
+            io: console_io,
+            reads: Vec::new(),
+            writes: Vec::new(),
         }
     }
 
-    pub fn set_mem(&mut self, addr: u16, val: u16) {
-        self.data[addr as usize] = val;
+    pub fn set_mem(&mut self, addr: u16, val: u16) -> Result<(), VmError> {
+        self.writes.push(MemWrite {
+            addr,
+            old: self.data[addr as usize],
+            new: val,
+        });
+
+        match addr {
+            // only the interrupt enable bit of KBSR is writable
+            KBSR => self.data[addr as usize] = val & KBSR_IE,
+            // KBDR and DSR are read-only
+            KBDR | DSR => {}
+            DDR => {
+                self.data[addr as usize] = val;
+                self.io.put_char(val as u8);
+            }
+            MCR => self.data[addr as usize] = val,
+            _ if addr >= DEVICE_SPACE => return Err(VmError::UnmappedMmio { addr }),
+            _ => self.data[addr as usize] = val,
+        }
+        Ok(())
+    }
+
+    pub fn get_mem(&mut self, addr: u16) -> Result<u16, VmError> {
+        let value = self.fetch(addr)?;
+        self.reads.push(MemRead { addr, value });
+        Ok(value)
     }
 
-    pub fn get_mem(&mut self, addr: u16) -> u16 {
-        if addr >= 0xFE00 {
-            return match addr {
-                0xFE00 => {
+    /// Read a word like get_mem, but without logging it (for instruction fetches and polling)
+    pub fn fetch(&mut self, addr: u16) -> Result<u16, VmError> {
+        if addr >= DEVICE_SPACE {
+            return Ok(match addr {
+                KBSR => {
+                    let ie = self.data[addr as usize] & KBSR_IE;
                     if self.io.check_key() {
-                        1 << 15
+                        STATUS_READY | ie
                     } else {
-                        0
+                        ie
                     }
                 }
-                0xFE02 => {
-                    let key = match self.io.get_key() {
-                        Some(key) => key as u16,
-                        None => self.data[0xFE02],
-                    };
-                    return key;
+                KBDR => {
+                    // hold on to the last key, in case KBDR is read again
+                    if let Some(key) = self.io.get_key() {
+                        self.data[addr as usize] = key as u16;
+                    }
+                    self.data[addr as usize]
                 }
-                _ => unimplemented!("mem-map: {:#X}", addr),
-            };
+                DSR => {
+                    if self.io.display_ready() {
+                        STATUS_READY
+                    } else {
+                        0
+                    }
+                }
+                DDR => self.data[addr as usize],
+                MCR => self.data[addr as usize],
+                _ => return Err(VmError::UnmappedMmio { addr }),
+            });
         }
-        return self.data[addr as usize];
+        Ok(self.data[addr as usize])
+    }
+
+    /// Take the reads and writes made since the last call
+    pub fn take_log(&mut self) -> (Vec<MemRead>, Vec<MemWrite>) {
+        (
+            std::mem::take(&mut self.reads),
+            std::mem::take(&mut self.writes),
+        )
+    }
+
+    /// Get the stored word at an address, without side effects
+    pub fn peek(&self, addr: u16) -> u16 {
+        self.data[addr as usize]
+    }
+
+    /// Set the stored word at an address, without side effects
+    pub fn poke(&mut self, addr: u16, val: u16) {
+        self.data[addr as usize] = val;
+    }
+
+    /// Check if the clock is running (MCR bit 15 is set)
+    pub fn clock_enabled(&self) -> bool {
+        self.data[MCR as usize] & MCR_CLOCK_ENABLE != 0
+    }
+
+    /// Check if the keyboard is requesting an interrupt (IE is set and a key is ready)
+    pub fn keyboard_interrupt(&mut self) -> bool {
+        self.data[KBSR as usize] & KBSR_IE != 0 && self.io.check_key()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_console::TestConsole;
+
+    #[test]
+    fn keyboard_registers() {
+        let mut console = TestConsole::with_keys(b"ab");
+        let mut mem = Memory::new(&mut console);
+
+        assert_eq!(mem.get_mem(KBSR).unwrap(), STATUS_READY);
+        assert_eq!(mem.get_mem(KBDR).unwrap(), 'a' as u16);
+        // only the interrupt enable bit can be written
+        mem.set_mem(KBSR, 0xFFFF).unwrap();
+        assert_eq!(mem.get_mem(KBSR).unwrap(), STATUS_READY | KBSR_IE);
+        assert!(mem.keyboard_interrupt());
+        // KBDR is read-only
+        mem.set_mem(KBDR, 'z' as u16).unwrap();
+        assert_eq!(mem.get_mem(KBDR).unwrap(), 'b' as u16);
+
+        // the last key stays in KBDR once the keys run out
+        assert_eq!(mem.get_mem(KBSR).unwrap(), KBSR_IE);
+        assert!(!mem.keyboard_interrupt());
+        assert_eq!(mem.get_mem(KBDR).unwrap(), 'b' as u16);
+    }
+
+    #[test]
+    fn display_registers() {
+        let mut console = TestConsole::default();
+        let mut mem = Memory::new(&mut console);
+
+        assert_eq!(mem.get_mem(DSR).unwrap(), STATUS_READY);
+        // DSR is read-only
+        mem.set_mem(DSR, 0).unwrap();
+        assert_eq!(mem.get_mem(DSR).unwrap(), STATUS_READY);
+        mem.set_mem(DDR, 'h' as u16).unwrap();
+        mem.set_mem(DDR, 'i' as u16).unwrap();
+        assert_eq!(mem.get_mem(DDR).unwrap(), 'i' as u16);
+
+        // the rest of the device page is not mapped
+        assert!(matches!(
+            mem.get_mem(0xFE08),
+            Err(VmError::UnmappedMmio { addr: 0xFE08 })
+        ));
+        assert!(matches!(
+            mem.set_mem(0xFFFD, 1),
+            Err(VmError::UnmappedMmio { addr: 0xFFFD })
+        ));
+
+        drop(mem);
+        assert_eq!(console.output, b"hi");
+    }
+
+    #[test]
+    fn machine_control_register() {
+        let mut console = TestConsole::default();
+        let mut mem = Memory::new(&mut console);
+
+        // the clock runs from power-on
+        assert_eq!(mem.get_mem(MCR).unwrap(), MCR_CLOCK_ENABLE);
+        assert!(mem.clock_enabled());
+        mem.set_mem(MCR, 0x1234).unwrap();
+        assert_eq!(mem.get_mem(MCR).unwrap(), 0x1234);
+        assert!(!mem.clock_enabled());
     }
 }
@@ -19,18 +19,35 @@ You should have received a copy of the GNU General Public License along with thi
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use byteorder::{BigEndian, ReadBytesExt};
-use std::{fs::File, io::BufReader};
+use crate::asm::Assembly;
+use std::{io::Read, ops::Range, path::Path};
 
+mod error;
 mod instruction;
+pub mod loader;
 mod memory;
+pub mod number;
+mod source;
+mod step;
+pub mod symbols;
 pub mod terminal_io;
 
+pub use error::{StopReason, VmError};
+pub(crate) use instruction::sign_extend;
+use instruction::Fault;
+pub use instruction::{get_opcode, OpCode};
+pub use loader::Segment;
+pub use memory::{DDR, DSR, KBDR, KBSR, MCR, MEM_SIZE};
+pub use source::{SourceFile, SourceLocation};
+pub use step::{MemRead, MemWrite, RegWrite, StepOutcome};
+pub use symbols::SymbolTable;
+
 ////////////////
 // registers
 ////////////////
 
-// condition flags (COND register)
+// condition flags (low 3 bits of PSR)
+#[allow(clippy::upper_case_acronyms)]
 enum CondFlags {
     // positive (P)
     POS = 1 << 0,
@@ -40,21 +57,44 @@ enum CondFlags {
     NEG = 1 << 2,
 }
 
-struct Registers {
+// processor status register (PSR) layout:
+//
+//  15    14-11  10-8      7-3    2 1 0
+//  priv  -      priority  -      N Z P
+//
+// privilege is 0 for supervisor mode and 1 for user mode
+const PSR_USER: u16 = 1 << 15;
+const PSR_PRIORITY_SHIFT: u16 = 8;
+const PSR_PRIORITY_MASK: u16 = 0b111 << PSR_PRIORITY_SHIFT;
+const PSR_COND_MASK: u16 = 0b111;
+
+/// The register file: R0-R7, PC, PSR and the saved stack pointers
+#[derive(Debug, Clone)]
+pub struct Registers {
     r0: u16,
     r1: u16,
     r2: u16,
     r3: u16,
     r4: u16,
     r5: u16,
+    // R6 is the stack pointer of whichever mode we are in;
+    // the other mode's stack pointer sits in saved_usp or saved_ssp
     r6: u16,
     r7: u16,
     pc: u16,
-    cond: u16,
+    psr: u16,
+    saved_usp: u16,
+    saved_ssp: u16,
     count: u16,
+    // bit `idx` is set when register `idx` is written (see `VM::step`)
+    written: u16,
 }
 
 const PC_START: usize = 0x3000;
+// the supervisor stack grows down from just under user space
+const SSP_START: u16 = 0x3000;
+// the user stack grows down from just under the device registers
+const USP_START: u16 = 0xFE00;
 
 impl Registers {
     fn new() -> Registers {
@@ -65,17 +105,21 @@ impl Registers {
             r3: 0,
             r4: 0,
             r5: 0,
-            r6: 0,
+            // we boot in supervisor mode, so R6 is the supervisor stack
+            r6: SSP_START,
             r7: 0,
             pc: PC_START as u16,
-            cond: 0,
+            psr: CondFlags::ZRO as u16,
+            saved_usp: USP_START,
+            saved_ssp: SSP_START,
             count: 0,
+            written: 0,
         }
     }
 
     /// Map an integer index to its register
-    fn register_reference(&mut self, idx: u16) -> &mut u16 {
-        match idx {
+    fn register_reference(&mut self, idx: u16) -> Result<&mut u16, VmError> {
+        Ok(match idx {
             0 => &mut self.r0,
             1 => &mut self.r1,
             2 => &mut self.r2,
@@ -85,43 +129,221 @@ impl Registers {
             6 => &mut self.r6,
             7 => &mut self.r7,
             8 => &mut self.pc,
-            9 => &mut self.cond,
-            _ => panic!("Invalid register {}", idx),
-        }
+            9 => &mut self.psr,
+            10 => &mut self.saved_usp,
+            11 => &mut self.saved_ssp,
+            _ => return Err(VmError::InvalidRegister(idx)),
+        })
     }
 
-    fn set_reg(&mut self, idx: u16, val: u16) {
-        *self.register_reference(idx) = val;
+    /// Write a register by index (see `get_reg`)
+    pub fn set_reg(&mut self, idx: u16, val: u16) -> Result<(), VmError> {
+        *self.register_reference(idx)? = val;
+        self.wrote(idx);
+        Ok(())
+    }
+
+    /// Read a register by index
+    ///
+    /// 0-7 are R0-R7, 8 is PC, 9 is PSR, 10 is the saved USP and 11 is the saved SSP.
+    pub fn get_reg(&self, idx: u16) -> Result<u16, VmError> {
+        match idx {
+            0..=7 => Ok(self.gprs()[idx as usize]),
+            8 => Ok(self.pc),
+            9 => Ok(self.psr),
+            10 => Ok(self.saved_usp),
+            11 => Ok(self.saved_ssp),
+            _ => Err(VmError::InvalidRegister(idx)),
+        }
     }
 
-    fn get_reg(&mut self, idx: u16) -> u16 {
-        let reg = &*self.register_reference(idx);
-        *reg
+    /// General purpose registers R0-R7
+    pub fn gprs(&self) -> [u16; 8] {
+        [
+            self.r0, self.r1, self.r2, self.r3, self.r4, self.r5, self.r6, self.r7,
+        ]
     }
 
-    /// Sets COND register based on a specific register's value
-    fn set_cond(&mut self, idx: u16) {
-        let val = self.get_reg(idx);
+    /// Sets condition flags based on a specific register's value
+    fn set_cond(&mut self, idx: u16) -> Result<(), VmError> {
+        let val = self.get_reg(idx)?;
 
-        if (val as i16) > 0 {
-            self.cond = CondFlags::POS as u16;
+        let flag = if (val as i16) > 0 {
+            CondFlags::POS
         } else if (val as i16) < 0 {
-            self.cond = CondFlags::NEG as u16;
-        } else if val == 0 {
-            self.cond = CondFlags::ZRO as u16;
+            CondFlags::NEG
+        } else {
+            CondFlags::ZRO
+        };
+        self.psr = (self.psr & !PSR_COND_MASK) | flag as u16;
+        self.wrote(9);
+        Ok(())
+    }
+
+    /// Program counter
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// Processor status register
+    pub fn psr(&self) -> u16 {
+        self.psr
+    }
+
+    /// User stack pointer, saved while in supervisor mode
+    pub fn saved_usp(&self) -> u16 {
+        self.saved_usp
+    }
+
+    /// Supervisor stack pointer, saved while in user mode
+    pub fn saved_ssp(&self) -> u16 {
+        self.saved_ssp
+    }
+
+    /// Condition flags (N, Z, P) stored in the low bits of PSR
+    pub fn cond(&self) -> u16 {
+        self.psr & PSR_COND_MASK
+    }
+
+    /// Whether the processor is running in user mode (as opposed to supervisor mode)
+    pub fn user_mode(&self) -> bool {
+        self.psr & PSR_USER != 0
+    }
+
+    /// Priority level (PL0-PL7) of the running program
+    pub fn priority(&self) -> u16 {
+        (self.psr & PSR_PRIORITY_MASK) >> PSR_PRIORITY_SHIFT
+    }
+
+    /// PSR spelled out, e.g. `Z, supervisor, PL0`
+    pub fn describe_psr(&self) -> String {
+        let mut condstr = String::new();
+        let flags = ["P", "Z", "N"];
+        for (i, flag) in flags.iter().enumerate() {
+            if (1 << i) & self.cond() != 0 {
+                condstr.push_str(flag);
+            }
         }
+        format!(
+            "{}, {}, PL{}",
+            condstr,
+            if self.user_mode() {
+                "user"
+            } else {
+                "supervisor"
+            },
+            self.priority()
+        )
+    }
+
+    fn set_priority(&mut self, level: u16) {
+        self.psr = (self.psr & !PSR_PRIORITY_MASK) | ((level & 0b111) << PSR_PRIORITY_SHIFT);
+        self.wrote(9);
     }
 
-    fn set_reg_with_cond(&mut self, idx: u16, val: u16) {
-        self.set_reg(idx, val);
-        self.set_cond(idx);
+    /// Note that register `idx` was written, even if its value did not change
+    fn wrote(&mut self, idx: u16) {
+        self.written |= 1 << idx;
+    }
+
+    /// Switch into supervisor mode, swapping to the supervisor stack if needed
+    fn enter_supervisor(&mut self) {
+        if self.user_mode() {
+            self.saved_usp = self.r6;
+            self.r6 = self.saved_ssp;
+            self.wrote(6);
+            self.wrote(10);
+        }
+        self.psr &= !PSR_USER;
+        self.wrote(9);
+    }
+
+    /// Replace PSR, swapping to the user stack if this drops us into user mode
+    fn restore_psr(&mut self, psr: u16) {
+        if !self.user_mode() && psr & PSR_USER != 0 {
+            self.saved_ssp = self.r6;
+            self.r6 = self.saved_usp;
+            self.wrote(6);
+            self.wrote(11);
+        }
+        self.psr = psr;
+        self.wrote(9);
+    }
+
+    fn set_reg_with_cond(&mut self, idx: u16, val: u16) -> Result<(), VmError> {
+        self.set_reg(idx, val)?;
+        self.set_cond(idx)
     }
 }
 
+////////////////
+// interrupts
+////////////////
+
+// base address of the interrupt vector table
+const INT_VECTOR_TABLE: u16 = 0x0100;
+
+/// Exceptions defined by the ISA, numbered by their interrupt vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    PrivilegeViolation = 0x00,
+    IllegalOpcode = 0x01,
+    AccessViolation = 0x02,
+}
+
+impl std::fmt::Display for Exception {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Exception::PrivilegeViolation => write!(f, "privilege mode violation"),
+            Exception::IllegalOpcode => write!(f, "illegal opcode"),
+            Exception::AccessViolation => write!(f, "access control violation"),
+        }
+    }
+}
+
+/// What to do with an exception whose vector table entry is empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionFallback {
+    /// Print a diagnostic and stop the VM
+    Halt,
+    /// Jump through the empty vector anyway, like the hardware would
+    Dispatch,
+}
+
+// keyboard interrupt vector and priority level
+const KBD_INT_VECTOR: u16 = 0x80;
+const KBD_PRIORITY: u16 = 4;
+
+////////////////
+// operating system
+////////////////
+
+// base address of the trap vector table
+const TRAP_VECTOR_TABLE: u16 = 0x0000;
+
+// default OS image, assembled from os/lc3os.asm
+const OS_IMAGE: &[u8] = include_bytes!("os/lc3os.obj");
+
+/// How TRAP instructions are serviced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapMode {
+    /// Standard trap routines are emulated natively (fast);
+    /// other vectors go through the trap vector table if a program installed a routine there
+    Native,
+    /// Every trap goes through the trap vector table to LC-3 service routines
+    Os,
+}
+
 ////////////////
 // VM interface
 ////////////////
 
+/// An LC-3 machine: memory, registers and the devices behind them
+//
 // NOTE
 // https://doc.rust-lang.org/book/ch10-03-lifetime-syntax.html
 // tl;dr the 'a is like a generic type name except it means that for some "lifetime" 'a, we will
@@ -130,84 +352,414 @@ impl Registers {
 pub struct VM<'a> {
     mem: memory::Memory<'a>,
     registers: Registers,
-    running: bool,
+    exception_fallback: ExceptionFallback,
+    trap_mode: TrapMode,
+    // set by the TRAP instruction, for the step outcome
+    trap_taken: Option<u8>,
+    // address ranges of the images loaded so far
+    loaded: Vec<Range<usize>>,
+    symbols: SymbolTable,
+    // assembly source of the programs loaded from it
+    sources: Vec<SourceFile>,
+    // address of the instruction run by the latest step
+    last_pc: u16,
     debug_state: DebugState,
 }
 
 impl VM<'_> {
-    pub fn new(keyboard_io: &mut dyn terminal_io::KeyboardIO) -> VM {
+    /// Create a VM whose keyboard and display are backed by `console_io`
+    pub fn new(console_io: &mut dyn terminal_io::ConsoleIO) -> VM<'_> {
         VM {
-            mem: memory::Memory::new(keyboard_io),
+            mem: memory::Memory::new(console_io),
             registers: Registers::new(),
-            running: false,
+            exception_fallback: ExceptionFallback::Halt,
+            trap_mode: TrapMode::Native,
+            trap_taken: None,
+            loaded: Vec::new(),
+            symbols: SymbolTable::new(),
+            sources: Vec::new(),
+            last_pc: 0,
             debug_state: DebugState::new(),
         }
     }
 
+    /// Print the machine state to stderr before every instruction
     pub fn set_debugging(&mut self, state: bool) {
         self.debug_state.debugging = state;
     }
 
-    pub fn read_program(&mut self, path: &String) {
-        let f = File::open(path).expect("Could not open program file");
-        let mut f = BufReader::new(f);
+    /// Choose what happens to exceptions that have no handler installed
+    pub fn set_exception_fallback(&mut self, fallback: ExceptionFallback) {
+        self.exception_fallback = fallback;
+    }
 
-        // NOTE
-        // LC-3 works with 16-bit words, in big endian.
-        // Endianness is the order of bytes *within a word*.
-        // Big endian is the "natural" order (left to right, start with most significant byte).
-        // This means that 0x3000 is encoded as 30 00.
-        // Little endian is commonly used on processors (see why at https://softwareengineering.stackexchange.com/questions/95556).
-        // For example, 0x12345678 (if we assume 32-bit words) would be encoded as 78 56 34 12.
-        // It turns out that the `hexdump` command uses 16-bit words and little-endian by default
-        // (at least on my machine).
-        // Therefore, it flips each pair of bytes.
-        // Meanwhile, `hed` uses big-endian.
-        // To make hexdump ignore words, pass the `-C` flag for a byte-by-byte output.
-        let base_addr = f
-            .read_u16::<BigEndian>()
-            .expect("Program file could not be read");
-
-        let mut addr = base_addr;
-        loop {
-            match f.read_u16::<BigEndian>() {
-                Ok(word) => {
-                    self.mem.set_mem(addr, word);
-                    addr += 1;
-                }
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                        break;
-                    } else {
-                        panic!("Can not read instruction: {:?}", e);
-                    }
+    /// Choose how TRAP instructions are serviced
+    pub fn set_trap_mode(&mut self, mode: TrapMode) {
+        self.trap_mode = mode;
+    }
+
+    /// Load the default OS image and service traps through it
+    pub fn load_os(&mut self) -> Result<(), VmError> {
+        self.load_segment(&loader::read_obj(OS_IMAGE)?)?;
+        self.set_trap_mode(TrapMode::Os);
+        Ok(())
+    }
+
+    /// Drop into user mode, on the user stack, like an OS does before starting a program
+    ///
+    /// System space (below x3000) and the device registers can then only be reached through
+    /// traps and interrupts; touching them directly raises an access control violation.
+    pub fn enter_user_mode(&mut self) {
+        let psr = self.registers.psr | PSR_USER;
+        self.registers.restore_psr(psr);
+    }
+
+    /// Load a program file into memory, and point PC at its (first) origin
+    ///
+    /// Binary objects (origin followed by big-endian words), lc3tools objects, and the textual
+    /// `.hex` and `.bin` formats are accepted; see [`loader::read_file`].
+    /// If a symbol table with the same name and a `.sym` extension sits next to the file,
+    /// it is loaded too.
+    /// Returns the ranges of addresses that were loaded, one per section.
+    pub fn read_program(&mut self, path: impl AsRef<Path>) -> Result<Vec<Range<usize>>, VmError> {
+        let path = path.as_ref();
+        let ranges = self.load_segments(&loader::read_file(path)?)?;
+
+        let sym_path = path.with_extension("sym");
+        if sym_path != path && sym_path.is_file() {
+            self.load_symbols(sym_path)?;
+        }
+        Ok(ranges)
+    }
+
+    /// Load a program image held in memory, like [`VM::read_program`]
+    ///
+    /// The format is detected from the contents.
+    pub fn load_program(&mut self, bytes: &[u8]) -> Result<Vec<Range<usize>>, VmError> {
+        let format = loader::ObjectFormat::detect(bytes);
+        self.load_segments(&loader::read_image(bytes, format)?)
+    }
+
+    /// Load a program image from a reader (a pipe, a socket, ...), like [`VM::read_program`]
+    pub fn read_program_from(&mut self, mut r: impl Read) -> Result<Vec<Range<usize>>, VmError> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        self.load_program(&bytes)
+    }
+
+    // load the sections of a program, and point PC at the first one
+    fn load_segments(&mut self, segments: &[Segment]) -> Result<Vec<Range<usize>>, VmError> {
+        let mut ranges = Vec::new();
+        for segment in segments {
+            ranges.push(self.load_segment(segment)?);
+        }
+        if let Some(first) = segments.first() {
+            self.registers.pc = first.origin;
+        }
+        Ok(ranges)
+    }
+
+    /// Load a program assembled from `source`, like [`VM::read_program`]
+    ///
+    /// Its labels are added to the VM's symbols, and the source is kept so that addresses can be
+    /// traced back to lines (see [`VM::source_location`]).
+    pub fn load_assembly(
+        &mut self,
+        file: &str,
+        source: &str,
+        assembly: &Assembly,
+    ) -> Result<Vec<Range<usize>>, VmError> {
+        let ranges = self.load_segments(&assembly.segments)?;
+        self.symbols.extend(&assembly.symbols);
+        self.sources
+            .push(SourceFile::new(file, source, assembly.source_map.clone()));
+        Ok(ranges)
+    }
+
+    /// Source line an address was assembled from, for programs loaded with [`VM::load_assembly`]
+    pub fn source_location(&self, addr: u16) -> Option<SourceLocation<'_>> {
+        self.sources.iter().find_map(|s| s.locate(addr))
+    }
+
+    /// Read a symbol table file, and add its labels to the VM's symbols
+    pub fn load_symbols(&mut self, path: impl AsRef<Path>) -> Result<(), VmError> {
+        let table = SymbolTable::read(path)?;
+        self.symbols.extend(&table);
+        Ok(())
+    }
+
+    /// Labels known to the VM, used in debugging output
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut SymbolTable {
+        &mut self.symbols
+    }
+
+    /// Describe an address relative to the closest label before it, e.g. `MAIN_LOOP+3`
+    ///
+    /// Only labels within the same loaded image are considered,
+    /// so that code with no symbols is not attributed to the end of another program.
+    pub fn symbolize(&self, addr: u16) -> Option<String> {
+        let floor = self
+            .loaded
+            .iter()
+            .find(|r| r.contains(&(addr as usize)))
+            .map_or(addr, |r| r.start as u16);
+
+        let (name, at) = self.symbols.nearest(addr, floor)?;
+        Some(match addr - at {
+            0 => name.to_string(),
+            off => format!("{}+{}", name, off),
+        })
+    }
+
+    /// Address of the instruction run (or being run) by the latest step
+    pub fn last_pc(&self) -> u16 {
+        self.last_pc
+    }
+
+    /// Copy a segment into memory, refusing to overwrite anything loaded before
+    pub fn load_segment(&mut self, segment: &Segment) -> Result<Range<usize>, VmError> {
+        segment.validate()?;
+
+        let range = segment.range();
+        if let Some(existing) = self
+            .loaded
+            .iter()
+            .find(|r| r.start < range.end && range.start < r.end)
+        {
+            return Err(VmError::Overlap {
+                existing: existing.clone(),
+                new: range,
+            });
+        }
+
+        for (addr, &word) in range.clone().zip(segment.words.iter()) {
+            self.mem.set_mem(addr as u16, word)?;
+        }
+
+        self.loaded.push(range.clone());
+        Ok(range)
+    }
+
+    /// Registers of the CPU
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    /// Read memory like the CPU does, which has side effects for device registers
+    pub fn read_mem(&mut self, addr: u16) -> Result<u16, VmError> {
+        self.mem.get_mem(addr)
+    }
+
+    /// Write memory like the CPU does, which has side effects for device registers
+    pub fn write_mem(&mut self, addr: u16, val: u16) -> Result<(), VmError> {
+        self.mem.set_mem(addr, val)
+    }
+
+    /// Look at the word stored at an address, without touching any device
+    pub fn peek_mem(&self, addr: u16) -> u16 {
+        self.mem.peek(addr)
+    }
+
+    /// Store a word at an address, without touching any device (so nothing is displayed)
+    ///
+    /// This is not logged in the step outcome.
+    pub fn poke_mem(&mut self, addr: u16, val: u16) {
+        self.mem.poke(addr, val)
+    }
+
+    /// Push a word onto the stack pointed to by R6
+    fn push_stack(&mut self, val: u16) -> Result<(), VmError> {
+        self.registers.r6 = self.registers.r6.wrapping_sub(1);
+        self.registers.wrote(6);
+        self.mem.set_mem(self.registers.r6, val)
+    }
+
+    /// Pop a word from the stack pointed to by R6
+    fn pop_stack(&mut self) -> Result<u16, VmError> {
+        let val = self.mem.get_mem(self.registers.r6)?;
+        self.registers.r6 = self.registers.r6.wrapping_add(1);
+        self.registers.wrote(6);
+        Ok(val)
+    }
+
+    /// Enter a service routine whose address is stored at `vector_addr`
+    ///
+    /// PSR and PC are saved on the supervisor stack, so the routine returns with RTI.
+    fn dispatch(&mut self, vector_addr: u16, priority: u16) -> Result<(), VmError> {
+        let psr = self.registers.psr;
+        self.registers.enter_supervisor();
+        self.push_stack(psr)?;
+        self.push_stack(self.registers.pc)?;
+        self.registers.set_priority(priority);
+        self.registers.pc = self.mem.get_mem(vector_addr)?;
+        Ok(())
+    }
+
+    /// Enter a service routine through the interrupt vector table
+    fn interrupt(&mut self, vector: u16, priority: u16) -> Result<(), VmError> {
+        self.dispatch(INT_VECTOR_TABLE + vector, priority)
+    }
+
+    /// Dispatch an exception raised by the instruction at `addr`
+    ///
+    /// PC already points past that instruction, so that is where the handler's RTI returns to.
+    fn raise_exception(&mut self, exception: Exception, addr: u16) -> Result<(), VmError> {
+        let vector = exception as u16;
+        let handler = self.mem.get_mem(INT_VECTOR_TABLE + vector)?;
+
+        if handler == 0 && self.exception_fallback == ExceptionFallback::Halt {
+            self.halt()?;
+            return Err(VmError::UnhandledException { exception, addr });
+        }
+
+        // exceptions run at the same priority as the program that caused them
+        self.interrupt(vector, self.registers.priority())
+    }
+
+    /// Service a pending device interrupt, if its priority is high enough
+    ///
+    /// Returns the vector of the interrupt taken.
+    fn check_interrupts(&mut self) -> Result<Option<u8>, VmError> {
+        if self.registers.priority() < KBD_PRIORITY && self.mem.keyboard_interrupt() {
+            self.interrupt(KBD_INT_VECTOR, KBD_PRIORITY)?;
+            return Ok(Some(KBD_INT_VECTOR as u8));
+        }
+        Ok(None)
+    }
+
+    /// Stop the clock by clearing the MCR clock enable bit, like an OS HALT routine does
+    fn halt(&mut self) -> Result<(), VmError> {
+        let mcr = self.mem.get_mem(memory::MCR)?;
+        self.mem
+            .set_mem(memory::MCR, mcr & !memory::MCR_CLOCK_ENABLE)
+    }
+
+    /// Start the clock by setting the MCR clock enable bit
+    fn start_clock(&mut self) -> Result<(), VmError> {
+        let mcr = self.mem.get_mem(memory::MCR)?;
+        self.mem
+            .set_mem(memory::MCR, mcr | memory::MCR_CLOCK_ENABLE)
+    }
+
+    /// Whether the clock is running (it stops on HALT)
+    pub fn running(&self) -> bool {
+        self.mem.clock_enabled()
+    }
+
+    /// Run until the clock stops
+    pub fn execute(&mut self) -> Result<StopReason, VmError> {
+        self.run_until(|_, _| false)
+    }
+
+    /// Run at most `n` instructions
+    pub fn run_for(&mut self, n: usize) -> Result<StopReason, VmError> {
+        if n == 0 {
+            return Ok(StopReason::Condition);
+        }
+
+        let mut count = 0;
+        self.run_until(|_, _| {
+            count += 1;
+            count >= n
+        })
+    }
+
+    /// Run until the clock stops, or `predicate` returns true after a step
+    pub fn run_until(
+        &mut self,
+        mut predicate: impl FnMut(&VM, &StepOutcome) -> bool,
+    ) -> Result<StopReason, VmError> {
+        self.start_clock()?;
+
+        while self.mem.clock_enabled() {
+            let outcome = self.step()?;
+            if predicate(self, &outcome) {
+                // halting on the very last step still counts as halting
+                if !outcome.halted {
+                    return Ok(StopReason::Condition);
                 }
             }
         }
+
+        Ok(StopReason::Halted)
     }
 
-    pub fn execute(&mut self) {
-        self.running = true;
+    /// Service pending interrupts, then execute a single instruction
+    ///
+    /// Once the clock is stopped (see [`VM::running`]), nothing is executed, and the outcome only
+    /// has `halted` set.
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        let mut outcome = StepOutcome {
+            pc_before: self.registers.pc,
+            pc_after: self.registers.pc,
+            ..Default::default()
+        };
+        if !self.mem.clock_enabled() {
+            outcome.halted = true;
+            return Ok(outcome);
+        }
+
+        let registers_before = self.registers.clone();
+        self.registers.written = 0;
+        self.mem.take_log();
+        self.trap_taken = None;
 
-        while self.running {
-            let instr = instruction::get_instruction(self);
+        outcome.interrupt = self.check_interrupts()?;
 
-            if self.debug_state.debugging {
-                DebugState::print_state(self);
+        let pc = self.registers.pc;
+        self.last_pc = pc;
+        match self.cycle(&mut outcome) {
+            Ok(()) => {}
+            Err(Fault::Exception(exception)) => {
+                outcome.exception = Some(exception);
+                self.raise_exception(exception, pc)?;
             }
+            Err(Fault::Error(e)) => return Err(e),
+        }
 
-            // NOTE
-            // remember PC points to the *next* instruction at all times
+        outcome.pc_after = self.registers.pc;
+        outcome.trap = self.trap_taken;
+        (outcome.mem_reads, outcome.mem_writes) = self.mem.take_log();
+        // PC is left out, since it is already covered above
+        for idx in (0..=11).filter(|&idx| idx != 8 && self.registers.written & (1 << idx) != 0) {
+            let old = registers_before.get_reg(idx)?;
+            let new = self.registers.get_reg(idx)?;
+            outcome.registers_written.push(RegWrite { idx, old, new });
+        }
+        outcome.halted = !self.mem.clock_enabled();
 
-            // disallow reading past memory bounds
-            if self.registers.pc as usize == memory::MEM_SIZE - 1 {
-                self.running = false
-            } else {
-                self.registers.pc += 1;
-            }
+        Ok(outcome)
+    }
 
-            instruction::execute_instruction(self, instr);
+    /// Fetch and execute a single instruction
+    fn cycle(&mut self, outcome: &mut StepOutcome) -> Result<(), Fault> {
+        if self.debug_state.debugging {
+            DebugState::print_state(self)?;
         }
+
+        // NOTE
+        // remember PC points to the *next* instruction at all times
+        // it moves on before the fetch is checked (like in the textbook's state machine), so that
+        // every exception, including an ACV on the fetch itself, saves the address after the
+        // instruction that caused it
+
+        // disallow reading past memory bounds
+        let pc = self.registers.pc;
+        self.registers.pc = pc.checked_add(1).ok_or(VmError::PcOverflow)?;
+
+        let instr = instruction::fetch_instruction(self, pc)?;
+        outcome.instruction = Some(instr);
+        outcome.opcode = Some(instruction::get_opcode(instr));
+
+        instruction::execute_instruction(self, instr)
     }
 }
 
@@ -225,29 +777,601 @@ impl DebugState {
     }
 
     /// Print current VM state
-    fn print_state(vm: &mut VM) {
+    fn print_state(vm: &mut VM) -> Result<(), VmError> {
         let instr = instruction::get_instruction(vm);
         let op_code = instruction::get_opcode(instr);
 
+        let label = vm
+            .symbolize(vm.registers.pc)
+            .map(|s| format!(" ({})", s))
+            .unwrap_or_default();
         eprintln!(
-            "PC: {:#x}, op: {:?}, params: {:#x}",
+            "PC: {:#x}{}, op: {:?}, params: {:#x}",
             vm.registers.pc,
+            label,
             op_code,
             instr & 0x7ff
         );
-        for i in 0..=7 {
-            eprintln!("R{}: {:#x}", i, vm.registers.get_reg(i));
+        if let Some(location) = vm.source_location(vm.registers.pc) {
+            eprintln!("  {}", location);
         }
-
-        let mut condstr = String::new();
-        let flags = ["P", "Z", "N"];
-        for i in 0..=2 {
-            if (1 << i) & vm.registers.cond != 0 {
-                condstr.push_str(flags[i]);
-            }
+        for (i, val) in vm.registers.gprs().iter().enumerate() {
+            eprintln!("R{}: {:#x}", i, val);
         }
-        eprintln!("COND: {:#x} ({})", vm.registers.cond, condstr);
+        eprintln!(
+            "PSR: {:#x} ({})",
+            vm.registers.psr,
+            vm.registers.describe_psr()
+        );
 
         eprintln!();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::test_console::TestConsole;
+    use memory::STATUS_READY;
+
+    // a VM with `source` loaded, PC at its origin
+    fn vm_with<'a>(console: &'a mut TestConsole, source: &str) -> VM<'a> {
+        let mut vm = VM::new(console);
+        let assembly = asm::assemble("t.asm", source).unwrap();
+        vm.load_assembly("t.asm", source, &assembly).unwrap();
+        vm
+    }
+
+    #[test]
+    fn waiting_for_a_key_logs_one_status_read() {
+        let mut console = TestConsole::with_keys(b"a");
+        console.key_delay = 10_000;
+        let mut vm = vm_with(&mut console, ".ORIG x3000\nGETC\n.END");
+
+        let outcome = vm.step().unwrap();
+        // the logged poll is the first one, from before the key came
+        assert_eq!(
+            outcome.mem_reads,
+            [
+                MemRead {
+                    addr: KBSR,
+                    value: 0
+                },
+                MemRead {
+                    addr: KBDR,
+                    value: 'a' as u16
+                }
+            ]
+        );
+        assert_eq!(vm.registers().get_reg(0).unwrap(), 'a' as u16);
+    }
+
+    #[test]
+    fn step_reports_memory_accesses() {
+        let source = "
+            .ORIG x3000
+            LDI R1, PTR
+            STI R1, PTR2
+            OUT
+            HALT
+        PTR .FILL x4000
+        PTR2 .FILL x4001
+            .END
+        ";
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, source);
+        vm.poke_mem(0x4000, 0x1234);
+        vm.registers_mut().set_reg(0, 'A' as u16).unwrap();
+
+        let ldi = vm.step().unwrap();
+        assert_eq!((ldi.pc_before, ldi.pc_after), (0x3000, 0x3001));
+        assert_eq!(ldi.opcode, Some(OpCode::LDI));
+        assert_eq!(
+            ldi.mem_reads,
+            [
+                MemRead {
+                    addr: 0x3004,
+                    value: 0x4000
+                },
+                MemRead {
+                    addr: 0x4000,
+                    value: 0x1234
+                }
+            ]
+        );
+        assert_eq!(ldi.mem_writes, []);
+        assert_eq!(
+            ldi.registers_written,
+            [
+                RegWrite {
+                    idx: 1,
+                    old: 0,
+                    new: 0x1234
+                },
+                RegWrite {
+                    idx: 9,
+                    old: 0b010,
+                    new: 0b001
+                },
+            ]
+        );
+
+        let sti = vm.step().unwrap();
+        assert_eq!(
+            sti.mem_reads,
+            [MemRead {
+                addr: 0x3005,
+                value: 0x4001
+            }]
+        );
+        assert_eq!(
+            sti.mem_writes,
+            [MemWrite {
+                addr: 0x4001,
+                old: 0,
+                new: 0x1234
+            }]
+        );
+        assert_eq!(sti.registers_written, []);
+
+        let out = vm.step().unwrap();
+        assert_eq!(out.trap, Some(0x21));
+        assert_eq!(
+            out.mem_reads,
+            [MemRead {
+                addr: DSR,
+                value: STATUS_READY
+            }]
+        );
+        assert_eq!(
+            out.mem_writes,
+            [MemWrite {
+                addr: DDR,
+                old: 0,
+                new: 0x41
+            }]
+        );
+        assert_eq!(
+            out.registers_written,
+            [RegWrite {
+                idx: 7,
+                old: 0,
+                new: 0x3003
+            }]
+        );
+        assert!(!out.halted);
+
+        drop(vm);
+        assert_eq!(console.output, b"A");
+    }
+
+    #[test]
+    fn step_reports_halting() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, ".ORIG x3000\nHALT\n.END");
+
+        let halt = vm.step().unwrap();
+        assert_eq!(halt.trap, Some(0x25));
+        assert_eq!(
+            halt.mem_writes,
+            [MemWrite {
+                addr: MCR,
+                old: 0x8000,
+                new: 0
+            }]
+        );
+        assert!(halt.halted);
+        assert!(!vm.running());
+
+        // nothing runs once the clock is stopped
+        let after = vm.step().unwrap();
+        assert!(after.halted);
+        assert_eq!(after.instruction, None);
+        assert_eq!((after.pc_before, after.pc_after), (0x3001, 0x3001));
+        assert_eq!(after.mem_writes, []);
+    }
+
+    #[test]
+    fn step_reports_exceptions() {
+        let source = ".ORIG x3000\n.FILL xD000\n.END";
+
+        // with a handler, the step ends at the handler
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, source);
+        vm.poke_mem(0x0101, 0x1000);
+        let outcome = vm.step().unwrap();
+        assert_eq!(outcome.opcode, Some(OpCode::RES));
+        assert_eq!(outcome.exception, Some(Exception::IllegalOpcode));
+        assert_eq!(outcome.pc_after, 0x1000);
+        // PSR and PC are pushed on the supervisor stack
+        assert_eq!(
+            outcome.mem_writes,
+            [
+                MemWrite {
+                    addr: 0x2FFF,
+                    old: 0,
+                    new: 0b010
+                },
+                MemWrite {
+                    addr: 0x2FFE,
+                    old: 0,
+                    new: 0x3001
+                },
+            ]
+        );
+
+        // without one, the step fails and the clock stops
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, source);
+        assert!(matches!(
+            vm.step(),
+            Err(VmError::UnhandledException {
+                exception: Exception::IllegalOpcode,
+                addr: 0x3000
+            })
+        ));
+        assert!(!vm.running());
+    }
+
+    #[test]
+    fn user_mode_runs_on_the_user_stack() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, ".ORIG x3000\nHALT\n.END");
+        vm.enter_user_mode();
+
+        let registers = vm.registers();
+        assert!(registers.user_mode());
+        assert_eq!(registers.psr(), PSR_USER | 0b010);
+        assert_eq!(registers.gprs()[6], USP_START);
+        assert_eq!(registers.saved_ssp(), SSP_START);
+        assert_eq!(registers.describe_psr(), "Z, user, PL0");
+    }
+
+    #[test]
+    fn rti_restores_pc_and_psr_from_the_supervisor_stack() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, ".ORIG x3000\nRTI\n.END");
+        // as if a user program at PL0 with P set was interrupted at x4000
+        vm.poke_mem(0x2FFF, PSR_USER | 0b001);
+        vm.poke_mem(0x2FFE, 0x4000);
+        vm.registers_mut().set_reg(6, 0x2FFE).unwrap();
+
+        vm.step().unwrap();
+        let registers = vm.registers();
+        assert_eq!(registers.pc(), 0x4000);
+        assert_eq!(registers.psr(), PSR_USER | 0b001);
+        // the supervisor stack is popped and put away, and the user stack comes back
+        assert_eq!(registers.saved_ssp(), 0x3000);
+        assert_eq!(registers.gprs()[6], USP_START);
+    }
+
+    #[test]
+    fn traps_from_user_mode_switch_stacks_and_back() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, ".ORIG x3000\nTRAP x40\n.END");
+        // a service routine that returns straight away
+        vm.poke_mem(0x0040, 0x1000);
+        vm.poke_mem(0x1000, 0x8000);
+        vm.enter_user_mode();
+        vm.registers_mut().set_reg(6, 0x5000).unwrap();
+
+        let trap = vm.step().unwrap();
+        let registers = vm.registers();
+        assert_eq!(registers.pc(), 0x1000);
+        assert!(!registers.user_mode());
+        assert_eq!(registers.gprs()[6], 0x2FFE);
+        assert_eq!(registers.saved_usp(), 0x5000);
+        assert_eq!(
+            trap.mem_writes,
+            [
+                MemWrite {
+                    addr: 0x2FFF,
+                    old: 0,
+                    new: PSR_USER | 0b010
+                },
+                MemWrite {
+                    addr: 0x2FFE,
+                    old: 0,
+                    new: 0x3001
+                },
+            ]
+        );
+
+        vm.step().unwrap();
+        let registers = vm.registers();
+        assert_eq!(registers.pc(), 0x3001);
+        assert!(registers.user_mode());
+        assert_eq!(registers.gprs()[6], 0x5000);
+        assert_eq!(registers.saved_ssp(), 0x3000);
+    }
+
+    #[test]
+    fn keyboard_interrupts() {
+        // an interrupt enabled keyboard with a key waiting, and a handler at x1000
+        fn vm_with_key(console: &mut TestConsole) -> VM<'_> {
+            let mut vm = vm_with(console, ".ORIG x3000\nADD R1, R1, #1\n.END");
+            vm.poke_mem(0x0180, 0x1000);
+            vm.poke_mem(0x1000, 0x1020); // ADD R0, R0, #0
+            vm.poke_mem(KBSR, 0x4000);
+            vm
+        }
+
+        let mut console = TestConsole::with_keys(b"k");
+        let mut vm = vm_with_key(&mut console);
+        let outcome = vm.step().unwrap();
+        assert_eq!(outcome.interrupt, Some(0x80));
+        // the handler's first instruction runs in the same step
+        assert_eq!(outcome.pc_after, 0x1001);
+        let registers = vm.registers();
+        assert_eq!(registers.priority(), 4);
+        assert!(!registers.user_mode());
+        // the interrupted instruction has not run, and is returned to
+        assert_eq!(registers.gprs()[1], 0);
+        assert_eq!(vm.peek_mem(0x2FFF), 0b010);
+        assert_eq!(vm.peek_mem(0x2FFE), 0x3000);
+
+        // not while running at PL4 or above
+        let mut console = TestConsole::with_keys(b"k");
+        let mut vm = vm_with_key(&mut console);
+        vm.registers_mut().set_reg(9, 4 << 8).unwrap();
+        let outcome = vm.step().unwrap();
+        assert_eq!(outcome.interrupt, None);
+        assert_eq!(outcome.pc_after, 0x3001);
+
+        // not without a key
+        let mut console = TestConsole::default();
+        let mut vm = vm_with_key(&mut console);
+        assert_eq!(vm.step().unwrap().interrupt, None);
+
+        // not with interrupts disabled
+        let mut console = TestConsole::with_keys(b"k");
+        let mut vm = vm_with_key(&mut console);
+        vm.poke_mem(KBSR, 0);
+        assert_eq!(vm.step().unwrap().interrupt, None);
+    }
+
+    // run one instruction from user mode, with handlers installed for every exception;
+    // returns the exception, where the handler was entered and the PC saved for its RTI
+    fn user_exception(source: &str, pc: u16) -> (Option<Exception>, u16, u16) {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, source);
+        for (vector, handler) in [(0x0100, 0x1000), (0x0101, 0x1100), (0x0102, 0x1200)] {
+            vm.poke_mem(vector, handler);
+        }
+        vm.enter_user_mode();
+        vm.registers_mut().set_pc(pc);
+
+        let outcome = vm.step().unwrap();
+        let registers = vm.registers();
+        assert!(!registers.user_mode(), "{:?}", outcome);
+        assert_eq!(vm.peek_mem(registers.gprs()[6] + 1), PSR_USER | 0b010);
+        let saved_pc = vm.peek_mem(registers.gprs()[6]);
+        (outcome.exception, outcome.pc_after, saved_pc)
+    }
+
+    #[test]
+    fn exceptions_dispatch_through_their_vectors() {
+        let cases = [
+            (
+                ".ORIG x3000\nRTI\n.END",
+                Exception::PrivilegeViolation,
+                0x1000,
+            ),
+            (
+                ".ORIG x3000\n.FILL xD000\n.END",
+                Exception::IllegalOpcode,
+                0x1100,
+            ),
+            // no service routine for this vector
+            (
+                ".ORIG x3000\nTRAP x55\n.END",
+                Exception::IllegalOpcode,
+                0x1100,
+            ),
+            (
+                ".ORIG x3000\nLD R0, #-2\n.END",
+                Exception::AccessViolation,
+                0x1200,
+            ),
+            (
+                ".ORIG x3000\nSTI R0, #0\n.END",
+                Exception::AccessViolation,
+                0x1200,
+            ),
+        ];
+        for (source, exception, handler) in cases {
+            let (raised, pc_after, saved_pc) = user_exception(source, 0x3000);
+            assert_eq!(raised, Some(exception), "{}", source);
+            assert_eq!(pc_after, handler, "{}", source);
+            assert_eq!(saved_pc, 0x3001, "{}", source);
+        }
+    }
+
+    #[test]
+    fn access_violations_on_fetch_save_the_next_pc_too() {
+        let (raised, pc_after, saved_pc) = user_exception(".ORIG x3000\nHALT\n.END", 0x2000);
+        assert_eq!(raised, Some(Exception::AccessViolation));
+        assert_eq!(pc_after, 0x1200);
+        assert_eq!(saved_pc, 0x2001);
+    }
+
+    #[test]
+    fn unhandled_exceptions() {
+        let source = ".ORIG x3000\nRTI\n.END";
+
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, source);
+        vm.enter_user_mode();
+        assert!(matches!(
+            vm.execute(),
+            Err(VmError::UnhandledException {
+                exception: Exception::PrivilegeViolation,
+                addr: 0x3000
+            })
+        ));
+        assert!(!vm.running());
+
+        // the hardware would jump through the empty vector, to x0000
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, source);
+        vm.set_exception_fallback(ExceptionFallback::Dispatch);
+        vm.enter_user_mode();
+        let outcome = vm.step().unwrap();
+        assert_eq!(outcome.exception, Some(Exception::PrivilegeViolation));
+        assert_eq!(outcome.pc_after, 0x0000);
+    }
+
+    #[test]
+    fn output_goes_through_the_display_registers() {
+        let source = r#"
+            .ORIG x3000
+        POLL LDI R1, DSR_PTR
+            BRzp POLL
+            LD R0, CHAR
+            STI R0, DDR_PTR
+            LEA R0, TEXT
+            PUTS
+            LEA R0, PACKED
+            PUTSP
+            HALT
+        DSR_PTR .FILL xFE04
+        DDR_PTR .FILL xFE06
+        CHAR .FILL x3E
+        TEXT .STRINGZ " hi"
+        PACKED .FILL x6F74
+            .FILL x0021
+            .END
+        "#;
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, source);
+        let mut displayed = Vec::new();
+        vm.run_until(|_, outcome| {
+            let writes = outcome.mem_writes.iter().filter(|w| w.addr == DDR);
+            displayed.extend(writes.map(|w| w.new as u8));
+            false
+        })
+        .unwrap();
+
+        drop(vm);
+        assert_eq!(displayed, b"> hito!");
+        assert_eq!(console.output, b"> hito!");
+    }
+
+    #[test]
+    fn clearing_the_clock_bit_halts() {
+        let source = "
+            .ORIG x3000
+            AND R0, R0, #0
+            STI R0, MCR_PTR
+            ADD R1, R1, #1
+            HALT
+        MCR_PTR .FILL xFFFE
+            .END
+        ";
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, source);
+
+        assert_eq!(vm.execute().unwrap(), StopReason::Halted);
+        assert!(!vm.running());
+        assert_eq!(vm.registers().pc(), 0x3002);
+        assert_eq!(vm.registers().gprs()[1], 0);
+
+        // running again starts the clock, and carries on after the store
+        assert_eq!(vm.run_for(1).unwrap(), StopReason::Condition);
+        assert_eq!(vm.registers().gprs()[1], 1);
+        assert_eq!(vm.execute().unwrap(), StopReason::Halted);
+        assert_eq!(vm.peek_mem(MCR), 0);
+    }
+
+    #[test]
+    fn os_traps_run_lc3_service_routines() {
+        let source = r#"
+            .ORIG x3000
+            GETC
+            OUT
+            LEA R0, TEXT
+            PUTS
+            IN
+            LEA R0, PACKED
+            PUTSP
+            HALT
+        TEXT .STRINGZ "<"
+        PACKED .FILL x3E3E
+            .FILL 0
+            .END
+        "#;
+        let mut console = TestConsole::with_keys(b"ab");
+        let mut vm = vm_with(&mut console, source);
+        vm.load_os().unwrap();
+        vm.enter_user_mode();
+
+        // the routine runs in supervisor mode, from the trap vector table
+        let getc = vm.step().unwrap();
+        assert_eq!(getc.trap, Some(0x20));
+        assert_eq!(getc.pc_after, vm.peek_mem(0x0020));
+        assert!(!vm.registers().user_mode());
+
+        assert_eq!(vm.execute().unwrap(), StopReason::Halted);
+        // halted in the HALT routine, as the OS stops the clock before returning
+        assert!(!vm.registers().user_mode());
+        assert_eq!(vm.peek_mem(MCR) & memory::MCR_CLOCK_ENABLE, 0);
+
+        drop(vm);
+        assert_eq!(console.output, b"a<b>>");
+    }
+
+    #[test]
+    fn programs_can_replace_os_routines() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, ".ORIG x3000\nOUT\n.END");
+        vm.load_os().unwrap();
+        // a routine that only increments R1
+        vm.poke_mem(0x0021, 0x1000);
+        vm.poke_mem(0x1000, 0x1261); // ADD R1, R1, #1
+        vm.poke_mem(0x1001, 0x8000); // RTI
+
+        vm.run_for(3).unwrap();
+        assert_eq!(vm.registers().pc(), 0x3001);
+        assert_eq!(vm.registers().gprs()[1], 1);
+        drop(vm);
+        assert_eq!(console.output, b"");
+    }
+
+    #[test]
+    fn errors_are_returned() {
+        let mut console = TestConsole::default();
+        let mut vm = vm_with(&mut console, ".ORIG x3000\nLDI R0, #0\n.FILL xFE08\n.END");
+
+        assert!(matches!(
+            vm.registers_mut().set_reg(12, 0),
+            Err(VmError::InvalidRegister(12))
+        ));
+        assert!(matches!(
+            vm.registers().get_reg(12),
+            Err(VmError::InvalidRegister(12))
+        ));
+
+        let err = vm.step().unwrap_err();
+        assert!(matches!(err, VmError::UnmappedMmio { addr: 0xFE08 }));
+        assert_eq!(err.to_string(), "no device register is mapped at 0xfe08");
+
+        vm.registers_mut().set_pc(0xFFFF);
+        assert!(matches!(vm.step(), Err(VmError::PcOverflow)));
+
+        assert!(matches!(
+            vm.read_program("does/not/exist.obj"),
+            Err(VmError::Io(_))
+        ));
+        assert!(matches!(
+            vm.load_program(&[0xFF]),
+            Err(VmError::MissingOrigin)
+        ));
+        assert!(matches!(
+            vm.load_program(&[0x40, 0x00, 0xFF]),
+            Err(VmError::TruncatedObject { bytes: 3 })
+        ));
     }
 }
@@ -0,0 +1,102 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// number syntax
+//////////////////////////////
+
+//! Numbers, as written in assembly, debugger expressions, symbol tables and on the command line.
+//!
+//! `#` starts a decimal number, `x` (or `0x`) a hexadecimal one, and `b` a binary one,
+//! and a `-` may follow the prefix. Digits without a prefix are read in a radix that depends on
+//! where they are written: decimal in assembly and expressions, hexadecimal in symbol tables,
+//! `.hex` images and addresses on the command line. In hexadecimal places, a leading `b` is a
+//! digit rather than the binary prefix, so `b800` means `xB800`.
+
+/// Split a number into its digits (with any sign) and their radix
+pub fn split_radix(text: &str, bare_radix: u32) -> (&str, u32) {
+    if let Some(rest) = text.strip_prefix('#') {
+        return (rest, 10);
+    }
+    if let Some(rest) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix(['x', 'X']))
+    {
+        return (rest, 16);
+    }
+    if bare_radix != 16 {
+        if let Some(rest) = text.strip_prefix(['b', 'B']) {
+            return (rest, 2);
+        }
+    }
+    (text, bare_radix)
+}
+
+/// Parse a number, reading digits without a prefix in `bare_radix`
+pub fn parse_number(text: &str, bare_radix: u32) -> Option<i32> {
+    let (digits, radix) = split_radix(text, bare_radix);
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, digits),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+/// Parse a number that fits in a word, either as signed (`#-1`) or unsigned (`xFFFF`)
+pub fn parse_word(text: &str, bare_radix: u32) -> Option<u16> {
+    match parse_number(text, bare_radix)? {
+        val @ -0x8000..=0xFFFF => Some(val as u16),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes() {
+        for bare_radix in [10, 16] {
+            assert_eq!(parse_number("#-5", bare_radix), Some(-5));
+            assert_eq!(parse_number("x-10", bare_radix), Some(-16));
+            assert_eq!(parse_number("0xAB", bare_radix), Some(0xAB));
+            assert_eq!(parse_number("XaB", bare_radix), Some(0xAB));
+            assert_eq!(parse_number("#", bare_radix), None);
+            assert_eq!(parse_number("x-", bare_radix), None);
+            assert_eq!(parse_number("#+5", bare_radix), None);
+        }
+    }
+
+    #[test]
+    fn bare_digits_depend_on_context() {
+        assert_eq!(parse_number("10", 10), Some(10));
+        assert_eq!(parse_number("10", 16), Some(0x10));
+        assert_eq!(parse_number("b10", 10), Some(0b10));
+        assert_eq!(parse_number("b800", 16), Some(0xB800));
+        assert_eq!(parse_number("b102", 10), None);
+        assert_eq!(parse_number("3A", 10), None);
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(parse_word("xFFFF", 16), Some(0xFFFF));
+        assert_eq!(parse_word("#-32768", 10), Some(0x8000));
+        assert_eq!(parse_word("#-1", 10), Some(0xFFFF));
+        assert_eq!(parse_word("x10000", 16), None);
+        assert_eq!(parse_word("#-32769", 10), None);
+        assert_eq!(parse_word("x7FFFFFFFFF", 16), None);
+    }
+}
@@ -0,0 +1,462 @@
+; This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+;
+; This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+;
+; You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+;
+; © 2024 dogeystamp <dogeystamp@disroot.org>
+
+;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;
+;;;;;; default LC-3 operating system image
+;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;
+
+; This is loaded below user space when the VM runs with `--os`.
+; It provides the trap vector table (x0000-x00FF), the interrupt vector table (x0100-x01FF),
+; the standard service routines and exception handlers.
+;
+; Service routines are entered with PSR and PC pushed on the supervisor stack
+; (see `op_trap` in src/vm/instruction.rs), so they all return with RTI.
+;
+; After editing this file, reassemble it into lc3os.obj with `lc3 asm lc3os.asm`.
+
+        .ORIG x0000
+
+;;;;;;;;;;;;;;;;
+; trap vector table
+;;;;;;;;;;;;;;;;
+
+        .FILL BAD_TRAP      ; x00
+        .FILL BAD_TRAP      ; x01
+        .FILL BAD_TRAP      ; x02
+        .FILL BAD_TRAP      ; x03
+        .FILL BAD_TRAP      ; x04
+        .FILL BAD_TRAP      ; x05
+        .FILL BAD_TRAP      ; x06
+        .FILL BAD_TRAP      ; x07
+        .FILL BAD_TRAP      ; x08
+        .FILL BAD_TRAP      ; x09
+        .FILL BAD_TRAP      ; x0A
+        .FILL BAD_TRAP      ; x0B
+        .FILL BAD_TRAP      ; x0C
+        .FILL BAD_TRAP      ; x0D
+        .FILL BAD_TRAP      ; x0E
+        .FILL BAD_TRAP      ; x0F
+        .FILL BAD_TRAP      ; x10
+        .FILL BAD_TRAP      ; x11
+        .FILL BAD_TRAP      ; x12
+        .FILL BAD_TRAP      ; x13
+        .FILL BAD_TRAP      ; x14
+        .FILL BAD_TRAP      ; x15
+        .FILL BAD_TRAP      ; x16
+        .FILL BAD_TRAP      ; x17
+        .FILL BAD_TRAP      ; x18
+        .FILL BAD_TRAP      ; x19
+        .FILL BAD_TRAP      ; x1A
+        .FILL BAD_TRAP      ; x1B
+        .FILL BAD_TRAP      ; x1C
+        .FILL BAD_TRAP      ; x1D
+        .FILL BAD_TRAP      ; x1E
+        .FILL BAD_TRAP      ; x1F
+        .FILL TRAP_GETC      ; x20
+        .FILL TRAP_OUT       ; x21
+        .FILL TRAP_PUTS      ; x22
+        .FILL TRAP_IN        ; x23
+        .FILL TRAP_PUTSP     ; x24
+        .FILL TRAP_HALT      ; x25
+        .FILL BAD_TRAP      ; x26
+        .FILL BAD_TRAP      ; x27
+        .FILL BAD_TRAP      ; x28
+        .FILL BAD_TRAP      ; x29
+        .FILL BAD_TRAP      ; x2A
+        .FILL BAD_TRAP      ; x2B
+        .FILL BAD_TRAP      ; x2C
+        .FILL BAD_TRAP      ; x2D
+        .FILL BAD_TRAP      ; x2E
+        .FILL BAD_TRAP      ; x2F
+        .FILL BAD_TRAP      ; x30
+        .FILL BAD_TRAP      ; x31
+        .FILL BAD_TRAP      ; x32
+        .FILL BAD_TRAP      ; x33
+        .FILL BAD_TRAP      ; x34
+        .FILL BAD_TRAP      ; x35
+        .FILL BAD_TRAP      ; x36
+        .FILL BAD_TRAP      ; x37
+        .FILL BAD_TRAP      ; x38
+        .FILL BAD_TRAP      ; x39
+        .FILL BAD_TRAP      ; x3A
+        .FILL BAD_TRAP      ; x3B
+        .FILL BAD_TRAP      ; x3C
+        .FILL BAD_TRAP      ; x3D
+        .FILL BAD_TRAP      ; x3E
+        .FILL BAD_TRAP      ; x3F
+        .FILL BAD_TRAP      ; x40
+        .FILL BAD_TRAP      ; x41
+        .FILL BAD_TRAP      ; x42
+        .FILL BAD_TRAP      ; x43
+        .FILL BAD_TRAP      ; x44
+        .FILL BAD_TRAP      ; x45
+        .FILL BAD_TRAP      ; x46
+        .FILL BAD_TRAP      ; x47
+        .FILL BAD_TRAP      ; x48
+        .FILL BAD_TRAP      ; x49
+        .FILL BAD_TRAP      ; x4A
+        .FILL BAD_TRAP      ; x4B
+        .FILL BAD_TRAP      ; x4C
+        .FILL BAD_TRAP      ; x4D
+        .FILL BAD_TRAP      ; x4E
+        .FILL BAD_TRAP      ; x4F
+        .FILL BAD_TRAP      ; x50
+        .FILL BAD_TRAP      ; x51
+        .FILL BAD_TRAP      ; x52
+        .FILL BAD_TRAP      ; x53
+        .FILL BAD_TRAP      ; x54
+        .FILL BAD_TRAP      ; x55
+        .FILL BAD_TRAP      ; x56
+        .FILL BAD_TRAP      ; x57
+        .FILL BAD_TRAP      ; x58
+        .FILL BAD_TRAP      ; x59
+        .FILL BAD_TRAP      ; x5A
+        .FILL BAD_TRAP      ; x5B
+        .FILL BAD_TRAP      ; x5C
+        .FILL BAD_TRAP      ; x5D
+        .FILL BAD_TRAP      ; x5E
+        .FILL BAD_TRAP      ; x5F
+        .FILL BAD_TRAP      ; x60
+        .FILL BAD_TRAP      ; x61
+        .FILL BAD_TRAP      ; x62
+        .FILL BAD_TRAP      ; x63
+        .FILL BAD_TRAP      ; x64
+        .FILL BAD_TRAP      ; x65
+        .FILL BAD_TRAP      ; x66
+        .FILL BAD_TRAP      ; x67
+        .FILL BAD_TRAP      ; x68
+        .FILL BAD_TRAP      ; x69
+        .FILL BAD_TRAP      ; x6A
+        .FILL BAD_TRAP      ; x6B
+        .FILL BAD_TRAP      ; x6C
+        .FILL BAD_TRAP      ; x6D
+        .FILL BAD_TRAP      ; x6E
+        .FILL BAD_TRAP      ; x6F
+        .FILL BAD_TRAP      ; x70
+        .FILL BAD_TRAP      ; x71
+        .FILL BAD_TRAP      ; x72
+        .FILL BAD_TRAP      ; x73
+        .FILL BAD_TRAP      ; x74
+        .FILL BAD_TRAP      ; x75
+        .FILL BAD_TRAP      ; x76
+        .FILL BAD_TRAP      ; x77
+        .FILL BAD_TRAP      ; x78
+        .FILL BAD_TRAP      ; x79
+        .FILL BAD_TRAP      ; x7A
+        .FILL BAD_TRAP      ; x7B
+        .FILL BAD_TRAP      ; x7C
+        .FILL BAD_TRAP      ; x7D
+        .FILL BAD_TRAP      ; x7E
+        .FILL BAD_TRAP      ; x7F
+        .FILL BAD_TRAP      ; x80
+        .FILL BAD_TRAP      ; x81
+        .FILL BAD_TRAP      ; x82
+        .FILL BAD_TRAP      ; x83
+        .FILL BAD_TRAP      ; x84
+        .FILL BAD_TRAP      ; x85
+        .FILL BAD_TRAP      ; x86
+        .FILL BAD_TRAP      ; x87
+        .FILL BAD_TRAP      ; x88
+        .FILL BAD_TRAP      ; x89
+        .FILL BAD_TRAP      ; x8A
+        .FILL BAD_TRAP      ; x8B
+        .FILL BAD_TRAP      ; x8C
+        .FILL BAD_TRAP      ; x8D
+        .FILL BAD_TRAP      ; x8E
+        .FILL BAD_TRAP      ; x8F
+        .FILL BAD_TRAP      ; x90
+        .FILL BAD_TRAP      ; x91
+        .FILL BAD_TRAP      ; x92
+        .FILL BAD_TRAP      ; x93
+        .FILL BAD_TRAP      ; x94
+        .FILL BAD_TRAP      ; x95
+        .FILL BAD_TRAP      ; x96
+        .FILL BAD_TRAP      ; x97
+        .FILL BAD_TRAP      ; x98
+        .FILL BAD_TRAP      ; x99
+        .FILL BAD_TRAP      ; x9A
+        .FILL BAD_TRAP      ; x9B
+        .FILL BAD_TRAP      ; x9C
+        .FILL BAD_TRAP      ; x9D
+        .FILL BAD_TRAP      ; x9E
+        .FILL BAD_TRAP      ; x9F
+        .FILL BAD_TRAP      ; xA0
+        .FILL BAD_TRAP      ; xA1
+        .FILL BAD_TRAP      ; xA2
+        .FILL BAD_TRAP      ; xA3
+        .FILL BAD_TRAP      ; xA4
+        .FILL BAD_TRAP      ; xA5
+        .FILL BAD_TRAP      ; xA6
+        .FILL BAD_TRAP      ; xA7
+        .FILL BAD_TRAP      ; xA8
+        .FILL BAD_TRAP      ; xA9
+        .FILL BAD_TRAP      ; xAA
+        .FILL BAD_TRAP      ; xAB
+        .FILL BAD_TRAP      ; xAC
+        .FILL BAD_TRAP      ; xAD
+        .FILL BAD_TRAP      ; xAE
+        .FILL BAD_TRAP      ; xAF
+        .FILL BAD_TRAP      ; xB0
+        .FILL BAD_TRAP      ; xB1
+        .FILL BAD_TRAP      ; xB2
+        .FILL BAD_TRAP      ; xB3
+        .FILL BAD_TRAP      ; xB4
+        .FILL BAD_TRAP      ; xB5
+        .FILL BAD_TRAP      ; xB6
+        .FILL BAD_TRAP      ; xB7
+        .FILL BAD_TRAP      ; xB8
+        .FILL BAD_TRAP      ; xB9
+        .FILL BAD_TRAP      ; xBA
+        .FILL BAD_TRAP      ; xBB
+        .FILL BAD_TRAP      ; xBC
+        .FILL BAD_TRAP      ; xBD
+        .FILL BAD_TRAP      ; xBE
+        .FILL BAD_TRAP      ; xBF
+        .FILL BAD_TRAP      ; xC0
+        .FILL BAD_TRAP      ; xC1
+        .FILL BAD_TRAP      ; xC2
+        .FILL BAD_TRAP      ; xC3
+        .FILL BAD_TRAP      ; xC4
+        .FILL BAD_TRAP      ; xC5
+        .FILL BAD_TRAP      ; xC6
+        .FILL BAD_TRAP      ; xC7
+        .FILL BAD_TRAP      ; xC8
+        .FILL BAD_TRAP      ; xC9
+        .FILL BAD_TRAP      ; xCA
+        .FILL BAD_TRAP      ; xCB
+        .FILL BAD_TRAP      ; xCC
+        .FILL BAD_TRAP      ; xCD
+        .FILL BAD_TRAP      ; xCE
+        .FILL BAD_TRAP      ; xCF
+        .FILL BAD_TRAP      ; xD0
+        .FILL BAD_TRAP      ; xD1
+        .FILL BAD_TRAP      ; xD2
+        .FILL BAD_TRAP      ; xD3
+        .FILL BAD_TRAP      ; xD4
+        .FILL BAD_TRAP      ; xD5
+        .FILL BAD_TRAP      ; xD6
+        .FILL BAD_TRAP      ; xD7
+        .FILL BAD_TRAP      ; xD8
+        .FILL BAD_TRAP      ; xD9
+        .FILL BAD_TRAP      ; xDA
+        .FILL BAD_TRAP      ; xDB
+        .FILL BAD_TRAP      ; xDC
+        .FILL BAD_TRAP      ; xDD
+        .FILL BAD_TRAP      ; xDE
+        .FILL BAD_TRAP      ; xDF
+        .FILL BAD_TRAP      ; xE0
+        .FILL BAD_TRAP      ; xE1
+        .FILL BAD_TRAP      ; xE2
+        .FILL BAD_TRAP      ; xE3
+        .FILL BAD_TRAP      ; xE4
+        .FILL BAD_TRAP      ; xE5
+        .FILL BAD_TRAP      ; xE6
+        .FILL BAD_TRAP      ; xE7
+        .FILL BAD_TRAP      ; xE8
+        .FILL BAD_TRAP      ; xE9
+        .FILL BAD_TRAP      ; xEA
+        .FILL BAD_TRAP      ; xEB
+        .FILL BAD_TRAP      ; xEC
+        .FILL BAD_TRAP      ; xED
+        .FILL BAD_TRAP      ; xEE
+        .FILL BAD_TRAP      ; xEF
+        .FILL BAD_TRAP      ; xF0
+        .FILL BAD_TRAP      ; xF1
+        .FILL BAD_TRAP      ; xF2
+        .FILL BAD_TRAP      ; xF3
+        .FILL BAD_TRAP      ; xF4
+        .FILL BAD_TRAP      ; xF5
+        .FILL BAD_TRAP      ; xF6
+        .FILL BAD_TRAP      ; xF7
+        .FILL BAD_TRAP      ; xF8
+        .FILL BAD_TRAP      ; xF9
+        .FILL BAD_TRAP      ; xFA
+        .FILL BAD_TRAP      ; xFB
+        .FILL BAD_TRAP      ; xFC
+        .FILL BAD_TRAP      ; xFD
+        .FILL BAD_TRAP      ; xFE
+        .FILL BAD_TRAP      ; xFF
+
+;;;;;;;;;;;;;;;;
+; interrupt vector table
+;;;;;;;;;;;;;;;;
+
+        .FILL EXC_PRIV      ; x00 privilege mode violation
+        .FILL EXC_ILLEGAL   ; x01 illegal opcode
+        .FILL EXC_ACV       ; x02 access control violation
+        ; device interrupts (e.g. the keyboard at x80) are left for programs to install
+        .BLKW xFD
+
+;;;;;;;;;;;;;;;;
+; service routines
+;;;;;;;;;;;;;;;;
+
+; GETC: read a character from the keyboard into R0
+TRAP_GETC
+        LDI R0, OS_KBSR
+        BRzp TRAP_GETC
+        LDI R0, OS_KBDR
+        RTI
+
+; OUT: write the character in R0 to the display
+TRAP_OUT
+        ADD R6, R6, #-1
+        STR R1, R6, #0
+OUT_WAIT
+        LDI R1, OS_DSR
+        BRzp OUT_WAIT
+        STI R0, OS_DDR
+        LDR R1, R6, #0
+        ADD R6, R6, #1
+        RTI
+
+; PUTS: write the null-terminated string at R0, one character per word
+TRAP_PUTS
+        ADD R6, R6, #-3
+        STR R0, R6, #0
+        STR R1, R6, #1
+        STR R2, R6, #2
+        ADD R1, R0, #0
+PUTS_LOOP
+        LDR R0, R1, #0
+        BRz PUTS_DONE
+PUTS_WAIT
+        LDI R2, OS_DSR
+        BRzp PUTS_WAIT
+        STI R0, OS_DDR
+        ADD R1, R1, #1
+        BRnzp PUTS_LOOP
+PUTS_DONE
+        LDR R0, R6, #0
+        LDR R1, R6, #1
+        LDR R2, R6, #2
+        ADD R6, R6, #3
+        RTI
+
+; IN: read a character from the keyboard into R0 and echo it
+TRAP_IN
+        ADD R6, R6, #-1
+        STR R1, R6, #0
+IN_POLL
+        LDI R0, OS_KBSR
+        BRzp IN_POLL
+        LDI R0, OS_KBDR
+IN_WAIT
+        LDI R1, OS_DSR
+        BRzp IN_WAIT
+        STI R0, OS_DDR
+        LDR R1, R6, #0
+        ADD R6, R6, #1
+        RTI
+
+; PUTSP: write the null-terminated string at R0, two characters per word (low byte first)
+TRAP_PUTSP
+        ADD R6, R6, #-4
+        STR R0, R6, #0
+        STR R1, R6, #1
+        STR R2, R6, #2
+        STR R3, R6, #3
+        ADD R1, R0, #0
+PUTSP_LOOP
+        LDR R2, R1, #0
+        LD R3, OS_LOW_BYTE
+        AND R0, R2, R3
+        BRz PUTSP_DONE
+PUTSP_WAIT_LOW
+        LDI R3, OS_DSR
+        BRzp PUTSP_WAIT_LOW
+        STI R0, OS_DDR
+        ; there is no right shift, so rebuild the high byte bit by bit
+        AND R0, R0, #0
+        AND R3, R3, #0
+        ADD R3, R3, #8
+PUTSP_SHIFT
+        ADD R0, R0, R0
+        ADD R2, R2, #0
+        BRzp PUTSP_NO_BIT
+        ADD R0, R0, #1
+PUTSP_NO_BIT
+        ADD R2, R2, R2
+        ADD R3, R3, #-1
+        BRp PUTSP_SHIFT
+        ADD R0, R0, #0
+        BRz PUTSP_DONE
+PUTSP_WAIT_HIGH
+        LDI R3, OS_DSR
+        BRzp PUTSP_WAIT_HIGH
+        STI R0, OS_DDR
+        ADD R1, R1, #1
+        BRnzp PUTSP_LOOP
+PUTSP_DONE
+        LDR R0, R6, #0
+        LDR R1, R6, #1
+        LDR R2, R6, #2
+        LDR R3, R6, #3
+        ADD R6, R6, #4
+        RTI
+
+; HALT: stop the clock by clearing bit 15 of the MCR
+TRAP_HALT
+        ADD R6, R6, #-2
+        STR R0, R6, #0
+        STR R1, R6, #1
+        LDI R0, OS_MCR
+        LD R1, OS_CLOCK_OFF
+        AND R0, R0, R1
+        STI R0, OS_MCR
+        ; we only get here if something restarts the clock
+        LDR R0, R6, #0
+        LDR R1, R6, #1
+        ADD R6, R6, #2
+        RTI
+
+;;;;;;;;;;;;;;;;
+; error handlers
+;;;;;;;;;;;;;;;;
+
+BAD_TRAP
+        LEA R0, MSG_BAD_TRAP
+        BRnzp OS_PANIC
+
+EXC_PRIV
+        LEA R0, MSG_PRIV
+        BRnzp OS_PANIC
+
+EXC_ILLEGAL
+        LEA R0, MSG_ILLEGAL
+        BRnzp OS_PANIC
+
+EXC_ACV
+        LEA R0, MSG_ACV
+        BRnzp OS_PANIC
+
+; print the message at R0 and halt for good
+OS_PANIC
+        PUTS
+OS_PANIC_HALT
+        HALT
+        BRnzp OS_PANIC_HALT
+
+;;;;;;;;;;;;;;;;
+; data
+;;;;;;;;;;;;;;;;
+
+OS_KBSR         .FILL xFE00
+OS_KBDR         .FILL xFE02
+OS_DSR          .FILL xFE04
+OS_DDR          .FILL xFE06
+OS_MCR          .FILL xFFFE
+OS_CLOCK_OFF    .FILL x7FFF
+OS_LOW_BYTE     .FILL x00FF
+
+MSG_BAD_TRAP    .STRINGZ "\n--- undefined trap executed, halting ---\n"
+MSG_PRIV        .STRINGZ "\n--- privilege mode violation, halting ---\n"
+MSG_ILLEGAL     .STRINGZ "\n--- illegal opcode, halting ---\n"
+MSG_ACV         .STRINGZ "\n--- access control violation, halting ---\n"
+
+        .END
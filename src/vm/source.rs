@@ -0,0 +1,66 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// source mapping
+//////////////////////////////
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Assembly source of a program, and the line each of its words came from
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    name: String,
+    lines: Vec<String>,
+    // address -> 1-based line number
+    map: BTreeMap<u16, usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, source: &str, map: BTreeMap<u16, usize>) -> SourceFile {
+        SourceFile {
+            name: name.to_string(),
+            lines: source.lines().map(str::to_string).collect(),
+            map,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Line an address was assembled from
+    pub fn locate(&self, addr: u16) -> Option<SourceLocation<'_>> {
+        let &line = self.map.get(&addr)?;
+        Some(SourceLocation {
+            file: &self.name,
+            line,
+            text: self.lines.get(line - 1).map_or("", |s| s.trim()),
+        })
+    }
+}
+
+/// A line of assembly source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    /// 1-based line number
+    pub line: usize,
+    /// Text of the line, without surrounding whitespace
+    pub text: &'a str,
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.text)
+    }
+}
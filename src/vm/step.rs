@@ -0,0 +1,67 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// single-step outcomes
+//////////////////////////////
+
+use crate::vm::instruction::OpCode;
+use crate::vm::Exception;
+
+/// A read from memory, with the word it got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemRead {
+    pub addr: u16,
+    pub value: u16,
+}
+
+/// A write to memory, with the word that was there before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemWrite {
+    pub addr: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// A write to a register (see `Registers::get_reg` for the indices)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegWrite {
+    pub idx: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// Everything that happened during one call to `VM::step`
+#[derive(Debug, Clone, Default)]
+pub struct StepOutcome {
+    /// PC before the step (before any interrupt was taken)
+    pub pc_before: u16,
+    /// PC after the step
+    pub pc_after: u16,
+    /// Instruction word that was executed, if fetching it succeeded
+    pub instruction: Option<u16>,
+    /// Opcode of the executed instruction
+    pub opcode: Option<OpCode>,
+    /// Registers (other than PC) that were written, even with the value they already held
+    pub registers_written: Vec<RegWrite>,
+    /// Memory reads, in order, excluding the instruction fetch
+    pub mem_reads: Vec<MemRead>,
+    /// Memory writes, in order
+    pub mem_writes: Vec<MemWrite>,
+    /// Trap vector, if the instruction was a TRAP
+    pub trap: Option<u8>,
+    /// Interrupt vector, if a device interrupt was taken before the instruction
+    pub interrupt: Option<u8>,
+    /// Exception raised by the instruction, if any
+    pub exception: Option<Exception>,
+    /// Whether the clock is stopped after this step (e.g. by HALT)
+    pub halted: bool,
+}
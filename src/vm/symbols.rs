@@ -0,0 +1,181 @@
+/*
+
+This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation; either version 3 of the License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not, see https://www.gnu.org/licenses/.
+
+© 2024 dogeystamp <dogeystamp@disroot.org>
+*/
+
+//////////////////////////////
+////// symbol tables
+//////////////////////////////
+
+//! Symbol tables map labels to addresses.
+//!
+//! Two text formats are understood:
+//!
+//! - the `.sym` files written by `lc3as`, where every symbol is a comment line (indented with a tab):
+//!   ```text
+//!   // Symbol table
+//!   // Scope level 0:
+//!   //    Symbol Name       Page Address
+//!   //    ----------------  ------------
+//!   //    MAIN              3000
+//!   ```
+//! - a plain format of one `LABEL x3000` pair per line, with `;` starting a comment.
+//!
+//! The format is picked per line: lines starting with `//` are read as `lc3as` output
+//! (and its header lines are skipped), the rest as the plain format.
+
+use crate::vm::{number, VmError};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+
+/// Labels and the addresses they stand for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    // labels defined at each address, oldest first
+    by_addr: BTreeMap<u16, Vec<String>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    /// Parse a symbol table in either `lc3as` or plain format
+    pub fn parse(text: &str) -> Result<SymbolTable, VmError> {
+        let mut table = SymbolTable::new();
+
+        for (i, line) in text.lines().enumerate() {
+            if let Some(rest) = line.strip_prefix("//") {
+                // anything that is not exactly `NAME ADDR` is part of the header
+                let fields: Vec<&str> = rest.split_whitespace().collect();
+                if let [name, addr] = fields[..] {
+                    if let Some(addr) = number::parse_word(addr, 16) {
+                        table.insert(name, addr);
+                    }
+                }
+                continue;
+            }
+
+            let content = line.split(';').next().unwrap_or("").trim();
+            if content.is_empty() {
+                continue;
+            }
+
+            let bad_line = || VmError::BadSymbolLine {
+                line: i + 1,
+                text: line.to_string(),
+            };
+            let fields: Vec<&str> = content.split_whitespace().collect();
+            let [name, addr] = fields[..] else {
+                return Err(bad_line());
+            };
+            let addr = number::parse_word(addr, 16).ok_or_else(bad_line)?;
+            table.insert(name, addr);
+        }
+
+        Ok(table)
+    }
+
+    /// Read a symbol table file
+    pub fn read(path: impl AsRef<Path>) -> Result<SymbolTable, VmError> {
+        SymbolTable::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Define a label (a label defined again keeps its newest address)
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        match self.by_name.insert(name.to_string(), addr) {
+            Some(old) if old == addr => return,
+            Some(old) => {
+                // the next oldest label at the old address takes over
+                if let Some(names) = self.by_addr.get_mut(&old) {
+                    names.retain(|n| n != name);
+                    if names.is_empty() {
+                        self.by_addr.remove(&old);
+                    }
+                }
+            }
+            None => {}
+        }
+        self.by_addr.entry(addr).or_default().push(name.to_string());
+    }
+
+    /// Add every symbol of another table
+    pub fn extend(&mut self, other: &SymbolTable) {
+        for (name, addr) in other.sorted() {
+            self.insert(name, addr);
+        }
+    }
+
+    /// Address of a label
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Label defined exactly at an address
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.by_addr
+            .get(&addr)
+            .and_then(|names| names.first())
+            .map(String::as_str)
+    }
+
+    /// Closest label at or before an address, no lower than `floor`
+    pub fn nearest(&self, addr: u16, floor: u16) -> Option<(&str, u16)> {
+        if floor > addr {
+            return None;
+        }
+        self.by_addr
+            .range(floor..=addr)
+            .next_back()
+            // entries are removed once they have no names left
+            .map(|(&at, names)| (names[0].as_str(), at))
+    }
+
+    /// Every label, sorted by address (then name)
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.sorted().into_iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Render the table in the format written by `lc3as`
+    pub fn to_lc3as(&self) -> String {
+        let mut out = String::from(
+            "// Symbol table\n\
+             // Scope level 0:\n\
+             //\tSymbol Name       Page Address\n\
+             //\t----------------  ------------\n",
+        );
+
+        for (name, addr) in self.sorted() {
+            let _ = writeln!(out, "//\t{:<16}  {:04X}", name, addr);
+        }
+        out.push('\n');
+        out
+    }
+
+    // every label (including several at one address), ordered by address then name
+    fn sorted(&self) -> Vec<(&str, u16)> {
+        let mut symbols: Vec<(&str, u16)> = self
+            .by_name
+            .iter()
+            .map(|(name, &addr)| (name.as_str(), addr))
+            .collect();
+        symbols.sort_by_key(|&(name, addr)| (addr, name));
+        symbols
+    }
+}
@@ -21,16 +21,21 @@ use libc::STDIN_FILENO;
 
 extern crate ctrlc;
 
+use std::fs::File;
 use std::io;
-use std::io::Read;
+use std::io::{IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 ////////////////
-// keyboard I/O interface
+// keyboard/display I/O interface
 ////////////////
 
+/// Source of keypresses, behind KBSR and KBDR
 pub trait KeyboardIO {
     /// Poll stdin for a keypress
     fn get_key(&mut self) -> Option<u8>;
@@ -38,27 +43,70 @@ pub trait KeyboardIO {
     fn check_key(&mut self) -> bool;
 }
 
+/// Sink for characters, behind DSR and DDR
+pub trait DisplayIO {
+    /// Write a character to the display
+    fn put_char(&mut self, c: u8);
+    /// Check if the display can accept another character
+    fn display_ready(&mut self) -> bool {
+        true
+    }
+}
+
+/// Keyboard and display together, as seen through the memory-mapped device registers
+pub trait ConsoleIO: KeyboardIO + DisplayIO {}
+
+impl<T: KeyboardIO + DisplayIO> ConsoleIO for T {}
+
+/// Keyboard and display backed by the terminal (which is put in raw mode until this is dropped)
+///
+/// Keys are read from stdin, unless stdin is not a terminal (e.g. the program was piped in),
+/// in which case they are read from the controlling terminal.
 pub struct TerminalIO {
-    stdin_channel: Receiver<u8>,
+    stdin_channel: Arc<Mutex<Receiver<u8>>>,
     char: Option<u8>,
 }
 
 impl TerminalIO {
+    // no `Default`: creating one takes over the terminal, which should not happen implicitly
+    #[allow(clippy::new_without_default)]
     pub fn new() -> TerminalIO {
-        setup_termios();
+        let input: Box<dyn Read + Send> = if io::stdin().is_terminal() {
+            setup_termios(STDIN_FILENO);
+            Box::new(io::stdin())
+        } else {
+            match File::open("/dev/tty") {
+                Ok(tty) => {
+                    setup_termios(tty.as_raw_fd());
+                    Box::new(tty)
+                }
+                // no terminal at all, so just take whatever stdin has left
+                Err(_) => Box::new(io::stdin()),
+            }
+        };
+
         TerminalIO {
-            stdin_channel: Self::spawn_stdin_channel(),
+            stdin_channel: Arc::new(Mutex::new(Self::spawn_stdin_channel(input))),
             char: None,
         }
     }
 
-    fn spawn_stdin_channel() -> Receiver<u8> {
+    /// Line reader sharing this terminal's input, e.g. for prompts while the VM is paused
+    pub fn lines(&self) -> TerminalLines {
+        TerminalLines {
+            input: Arc::clone(&self.stdin_channel),
+        }
+    }
+
+    fn spawn_stdin_channel(mut input: Box<dyn Read + Send>) -> Receiver<u8> {
         // https://stackoverflow.com/questions/30012995
         let (tx, rx) = mpsc::channel::<u8>();
         let mut buffer: [u8; 1] = [0];
-        thread::spawn(move || loop {
-            let _ = io::stdin().lock().read_exact(&mut buffer);
-            let _ = tx.send(buffer[0]);
+        thread::spawn(move || {
+            // stop at the end of the input, which disconnects the channel
+            while input.read_exact(&mut buffer).is_ok() {
+                let _ = tx.send(buffer[0]);
+            }
         });
         rx
     }
@@ -80,34 +128,106 @@ impl KeyboardIO for TerminalIO {
     fn check_key(&mut self) -> bool {
         match self.char {
             Some(c) => true,
-            None => match self.stdin_channel.try_recv() {
+            None => match self.stdin_channel.lock().unwrap().try_recv() {
                 Ok(key) => {
                     self.char = Some(key);
                     true
                 }
-                Err(mpsc::TryRecvError::Empty) => false,
-                Err(mpsc::TryRecvError::Disconnected) => panic!("terminal keyboard stream broke"),
+                // after the end of the input, no more keys will ever come
+                Err(mpsc::TryRecvError::Empty) | Err(mpsc::TryRecvError::Disconnected) => false,
             },
         }
     }
 }
 
+impl DisplayIO for TerminalIO {
+    fn put_char(&mut self, c: u8) {
+        print!("{}", c as char);
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Reads whole lines from the terminal, see `TerminalIO::lines`
+pub struct TerminalLines {
+    input: Arc<Mutex<Receiver<u8>>>,
+}
+
+impl TerminalLines {
+    /// Read a line, without its newline
+    ///
+    /// The terminal is taken out of raw mode meanwhile, so the line is echoed and can be edited.
+    /// Returns `None` at the end of the input.
+    pub fn read_line(&mut self) -> Option<String> {
+        set_raw_mode(false);
+        let input = self.input.lock().unwrap();
+        let mut line = Vec::new();
+        let end = loop {
+            match input.recv() {
+                Ok(b'\n') => break false,
+                Ok(c) => line.push(c),
+                Err(_) => break true,
+            }
+        };
+        set_raw_mode(true);
+
+        if end && line.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&line).into_owned())
+        }
+    }
+}
+
+////////////////
+// CTRL-C
+////////////////
+
+// NOTE
+// CTRL-C normally exits (see `setup_termios`). A debugger instead wants it to pause the program,
+// so it can ask for that with `catch_interrupts`, and then poll `take_interrupt`.
+
+static CATCH_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Make CTRL-C set a flag (see `take_interrupt`) instead of exiting
+pub fn catch_interrupts(catch: bool) {
+    CATCH_INTERRUPTS.store(catch, Ordering::SeqCst);
+}
+
+/// Pause the debugged program as if CTRL-C was pressed
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Check if CTRL-C was pressed since the last call
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
 ////////////////
 // termios stuff
 ////////////////
 
+// terminal that was put in raw mode, or -1 if none was
+static TERMINAL_FD: AtomicI32 = AtomicI32::new(-1);
+
 /// Configure raw input (see termios(3) man-page)
-fn setup_termios() {
-    let mut term: Termios = Termios::from_fd(STDIN_FILENO).unwrap();
+fn setup_termios(fd: RawFd) {
+    let mut term: Termios = Termios::from_fd(fd).unwrap();
     // ICANON (canonical) is line-by-line input (i.e. press enter to send)
     // ECHO is showing the characters you type
     // what this means is that LC-3 will receive characters immediately and without displaying them
     term.c_lflag &= !(ICANON | ECHO);
     // TCSANOW: "the change occurs immediately"
-    tcsetattr(STDIN_FILENO, TCSANOW, &term).unwrap();
+    tcsetattr(fd, TCSANOW, &term).unwrap();
+    TERMINAL_FD.store(fd, Ordering::SeqCst);
 
     // when leaving the program we want to be polite and undo the above changes
     ctrlc::set_handler(|| {
+        if CATCH_INTERRUPTS.load(Ordering::SeqCst) {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            return;
+        }
         restore_terminal();
         // typical CTRL-C exit code
         std::process::exit(130);
@@ -117,8 +237,22 @@ fn setup_termios() {
 
 /// Restore terminal to initial state
 fn restore_terminal() {
+    set_raw_mode(false);
+}
+
+/// Switch the terminal set up by `setup_termios` in or out of raw input
+fn set_raw_mode(raw: bool) {
+    let fd = TERMINAL_FD.load(Ordering::SeqCst);
+    if fd < 0 {
+        return;
+    }
+
     // Ideally we'd store the original state but I was too lazy
-    let mut term: Termios = Termios::from_fd(STDIN_FILENO).unwrap();
-    term.c_lflag |= ICANON | ECHO;
-    tcsetattr(STDIN_FILENO, TCSANOW, &term).unwrap()
+    let mut term: Termios = Termios::from_fd(fd).unwrap();
+    if raw {
+        term.c_lflag &= !(ICANON | ECHO);
+    } else {
+        term.c_lflag |= ICANON | ECHO;
+    }
+    tcsetattr(fd, TCSANOW, &term).unwrap()
 }